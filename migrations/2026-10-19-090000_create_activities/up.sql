@@ -0,0 +1,14 @@
+CREATE TABLE activities (
+  id               BIGINT  PRIMARY KEY NOT NULL,
+  user_id          INTEGER NOT NULL REFERENCES users (id),
+  name             TEXT    NOT NULL,
+  distance         DOUBLE  NOT NULL,
+  moving_time      BIGINT  NOT NULL,
+  elapsed_time     BIGINT  NOT NULL,
+  start_date       BIGINT  NOT NULL,
+  kudos_count      INTEGER NOT NULL,
+  average_speed    DOUBLE  NOT NULL,
+  sport_type       TEXT    NOT NULL,
+  summary_polyline TEXT
+);
+CREATE INDEX activities_user_id ON activities (user_id);
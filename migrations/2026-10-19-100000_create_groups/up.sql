@@ -0,0 +1,14 @@
+CREATE TABLE groups (
+  id          INTEGER PRIMARY KEY NOT NULL,
+  name        TEXT    NOT NULL,
+  invite_code TEXT    NOT NULL UNIQUE,
+  owner_id    INTEGER NOT NULL REFERENCES users (id),
+  created_at  BIGINT  NOT NULL
+);
+CREATE TABLE group_members (
+  group_id  INTEGER NOT NULL REFERENCES groups (id),
+  user_id   INTEGER NOT NULL REFERENCES users (id),
+  joined_at BIGINT  NOT NULL,
+  PRIMARY KEY (group_id, user_id)
+);
+CREATE INDEX group_members_user_id ON group_members (user_id);
@@ -0,0 +1,7 @@
+CREATE TABLE cells (
+  user_id       INTEGER NOT NULL REFERENCES users (id),
+  cell          BIGINT  NOT NULL,
+  first_visited BIGINT  NOT NULL,
+  visits        INTEGER NOT NULL,
+  PRIMARY KEY (user_id, cell)
+);
@@ -0,0 +1,5 @@
+CREATE TABLE share_tokens (
+  user_id    INTEGER PRIMARY KEY NOT NULL REFERENCES users (id),
+  token_hash TEXT    NOT NULL UNIQUE,
+  created_at BIGINT  NOT NULL
+);
@@ -0,0 +1,9 @@
+CREATE TABLE privacy_zones (
+  id          INTEGER PRIMARY KEY NOT NULL,
+  user_id     INTEGER NOT NULL REFERENCES users (id),
+  lat         DOUBLE  NOT NULL,
+  lng         DOUBLE  NOT NULL,
+  radius      DOUBLE  NOT NULL,
+  count_cells BOOLEAN NOT NULL DEFAULT 1
+);
+CREATE INDEX privacy_zones_user_id ON privacy_zones (user_id);
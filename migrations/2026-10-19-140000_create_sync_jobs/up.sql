@@ -0,0 +1,11 @@
+CREATE TABLE sync_jobs (
+  id          INTEGER PRIMARY KEY NOT NULL,
+  user_id     INTEGER NOT NULL REFERENCES users (id),
+  kind        TEXT    NOT NULL,
+  status      TEXT    NOT NULL,
+  started_at  BIGINT  NOT NULL,
+  finished_at BIGINT  NOT NULL,
+  activities  INTEGER NOT NULL DEFAULT 0,
+  error       TEXT
+);
+CREATE INDEX sync_jobs_user_id ON sync_jobs (user_id);
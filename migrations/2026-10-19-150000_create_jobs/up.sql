@@ -0,0 +1,13 @@
+CREATE TABLE jobs (
+  id         INTEGER PRIMARY KEY NOT NULL,
+  user_id    INTEGER NOT NULL REFERENCES users (id),
+  status     TEXT    NOT NULL,
+  attempts   INTEGER NOT NULL DEFAULT 0,
+  last_error TEXT,
+  fetched    INTEGER NOT NULL DEFAULT 0,
+  total      INTEGER,
+  created_at BIGINT  NOT NULL,
+  updated_at BIGINT  NOT NULL
+);
+CREATE INDEX jobs_status ON jobs (status);
+CREATE INDEX jobs_user_id ON jobs (user_id);
@@ -0,0 +1,4 @@
+ALTER TABLE activities DROP COLUMN max_lat;
+ALTER TABLE activities DROP COLUMN max_lng;
+ALTER TABLE activities DROP COLUMN min_lat;
+ALTER TABLE activities DROP COLUMN min_lng;
@@ -0,0 +1,6 @@
+-- Bounding box of each activity's line, so tiles only load the activities they show
+-- NULL for activities without a line, and for ones stored before this
+ALTER TABLE activities ADD COLUMN min_lng DOUBLE;
+ALTER TABLE activities ADD COLUMN min_lat DOUBLE;
+ALTER TABLE activities ADD COLUMN max_lng DOUBLE;
+ALTER TABLE activities ADD COLUMN max_lat DOUBLE;
@@ -0,0 +1,315 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Cursor, Write};
+
+use flate2::write::GzEncoder;
+use geo::{Point, Rect};
+use h3o::CellIndex;
+use rocket::http::{ContentType, Header};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::{self, Responder, Response};
+use serde::{Deserialize, Serialize};
+
+use crate::error;
+use crate::h3::Attribution;
+use crate::models::{Activity, Region};
+use crate::sport::{Category, SportType};
+
+/// Activity with its line as an encoded polyline rather than coordinates
+/// and the same `new_cells` and `total_cells` as the GeoJSON properties
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct CompactActivity {
+    pub id: i64,
+    pub name: String,
+    pub distance: f64,
+    pub moving_time: i64,
+    pub elapsed_time: i64,
+    pub start_date: i64,
+    pub kudos_count: i32,
+    pub average_speed: f64,
+    pub sport_type: SportType,
+    pub polyline: Option<String>,
+    pub new_cells: Option<usize>,
+    pub total_cells: Option<usize>,
+}
+
+/// A smaller alternative to models::Data, with cells as raw H3 indexes
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct CompactData {
+    pub activities: Vec<CompactActivity>,
+    pub cells: Vec<u64>,
+    pub centroid: Option<Point>,
+    pub bbox: Option<Rect>,
+    pub regions: Vec<Region>,
+    pub sport_cells: BTreeMap<Category, Vec<u64>>,
+}
+
+impl CompactData {
+    pub fn new(
+        activities: Vec<Activity>,
+        attributions: &[Attribution],
+        cells: &[CellIndex],
+        home: Option<&Region>,
+        regions: Vec<Region>,
+        sport_cells: &BTreeMap<Category, Vec<CellIndex>>,
+    ) -> Self {
+        let attributions: HashMap<i64, &Attribution> =
+            attributions.iter().map(|a| (a.id, a)).collect();
+        let activities = activities
+            .into_iter()
+            .map(|a| CompactActivity {
+                id: a.id,
+                name: a.name,
+                distance: a.distance,
+                moving_time: a.moving_time,
+                elapsed_time: a.elapsed_time,
+                start_date: a.start_date.timestamp(),
+                kudos_count: a.kudos_count,
+                average_speed: a.average_speed,
+                sport_type: a.sport_type,
+                polyline: a
+                    .linestring
+                    .and_then(|ls| polyline::encode_coordinates(ls, 5).ok()),
+                new_cells: attributions.get(&a.id).map(|at| at.new_cells),
+                total_cells: attributions.get(&a.id).map(|at| at.total_cells),
+            })
+            .collect();
+        let to_u64 = |cells: &[CellIndex]| cells.iter().map(|c| u64::from(*c)).collect();
+        let cells = to_u64(cells);
+        let sport_cells = sport_cells
+            .iter()
+            .map(|(category, cells)| (*category, to_u64(cells)))
+            .collect();
+        CompactData {
+            activities,
+            cells,
+            centroid: home.map(|h| h.centroid),
+            bbox: home.map(|h| h.bbox),
+            regions,
+            sport_cells,
+        }
+    }
+}
+
+/// Content-Encoding to compress responses with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    /// Pick the best encoding from an Accept-Encoding header, preferring brotli
+    pub fn from_header(header: &str) -> Encoding {
+        let accepted: Vec<&str> = header
+            .split(',')
+            .filter_map(|part| {
+                let mut parts = part.split(';').map(str::trim);
+                let name = parts.next()?;
+                let refused = parts
+                    .any(|p| p.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0));
+                (!refused).then_some(name)
+            })
+            .collect();
+        if accepted.contains(&"br") {
+            Encoding::Brotli
+        } else if accepted.contains(&"gzip") {
+            Encoding::Gzip
+        } else {
+            Encoding::Identity
+        }
+    }
+
+    fn name(&self) -> Option<&'static str> {
+        match self {
+            Encoding::Identity => None,
+            Encoding::Gzip => Some("gzip"),
+            Encoding::Brotli => Some("br"),
+        }
+    }
+
+    pub fn encode(&self, data: Vec<u8>) -> Result<Vec<u8>, error::Error> {
+        let out = match self {
+            Encoding::Identity => data,
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&data)?;
+                encoder.finish()?
+            }
+            Encoding::Brotli => {
+                let mut out = Vec::new();
+                {
+                    let mut encoder = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+                    encoder.write_all(&data)?;
+                }
+                out
+            }
+        };
+        Ok(out)
+    }
+}
+
+/// What format the client asked for
+/// JSON unless `application/msgpack` is in the Accept header
+pub struct Negotiated {
+    pub msgpack: bool,
+    pub encoding: Encoding,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Negotiated {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Negotiated, Self::Error> {
+        let msgpack = request.accept().is_some_and(|accept| {
+            accept.media_types().any(|m| {
+                m.top() == "application"
+                    && ["msgpack", "x-msgpack", "vnd.msgpack"].contains(&m.sub().as_str())
+            })
+        });
+        let encoding = request
+            .headers()
+            .get_one("Accept-Encoding")
+            .map(Encoding::from_header)
+            .unwrap_or(Encoding::Identity);
+        Outcome::Success(Negotiated { msgpack, encoding })
+    }
+}
+
+/// MessagePack response, compressed up front so errors can be handled as usual
+pub struct MsgPack {
+    body: Vec<u8>,
+    encoding: Encoding,
+}
+
+impl MsgPack {
+    pub fn new(data: &CompactData, encoding: Encoding) -> Result<Self, error::Error> {
+        let body = rmp_serde::to_vec_named(data)?;
+        let body = encoding.encode(body)?;
+        Ok(MsgPack { body, encoding })
+    }
+}
+
+impl<'r> Responder<'r, 'static> for MsgPack {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response
+            .header(ContentType::new("application", "msgpack"))
+            .header(Header::new("Vary", "Accept, Accept-Encoding"))
+            .sized_body(self.body.len(), Cursor::new(self.body));
+        if let Some(name) = self.encoding.name() {
+            response.header(Header::new("Content-Encoding", name));
+        }
+        response.ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use crate::geo::fixture_activities;
+    use crate::{geo, h3};
+
+    use super::*;
+
+    #[test]
+    fn test_from_header() {
+        assert_eq!(Encoding::from_header("gzip, deflate, br"), Encoding::Brotli);
+        assert_eq!(Encoding::from_header("gzip;q=1.0, br;q=0"), Encoding::Gzip);
+        assert_eq!(Encoding::from_header("deflate"), Encoding::Identity);
+    }
+
+    #[test]
+    fn test_compact_round_trip() {
+        let sport_cells = h3::polyfill_by_category(&fixture_activities());
+        let cells = h3::union(sport_cells.values());
+        let attributions = h3::attribute(&fixture_activities(), &[]);
+        let data = CompactData::new(
+            fixture_activities(),
+            &attributions,
+            &cells,
+            None,
+            vec![],
+            &sport_cells,
+        );
+        let json = serde_json::to_vec(&geo::to_geojson(fixture_activities(), &[])).unwrap();
+
+        for encoding in [Encoding::Identity, Encoding::Gzip, Encoding::Brotli] {
+            let packed = MsgPack::new(&data, encoding).unwrap();
+            assert!(packed.body.len() < json.len());
+
+            let mut raw = Vec::new();
+            match encoding {
+                Encoding::Identity => raw = packed.body,
+                Encoding::Gzip => {
+                    flate2::read::GzDecoder::new(&packed.body[..])
+                        .read_to_end(&mut raw)
+                        .unwrap();
+                }
+                Encoding::Brotli => {
+                    brotli::Decompressor::new(&packed.body[..], 4096)
+                        .read_to_end(&mut raw)
+                        .unwrap();
+                }
+            }
+            let got: CompactData = rmp_serde::from_slice(&raw).unwrap();
+            assert_eq!(got, data);
+        }
+
+        let first = fixture_activities().remove(0).linestring.unwrap();
+        let decoded =
+            polyline::decode_polyline(data.activities[0].polyline.as_ref().unwrap(), 5).unwrap();
+        assert_eq!(decoded, first);
+    }
+
+    #[test]
+    fn test_compact_matches_json() {
+        let attributions = h3::attribute(&fixture_activities(), &[]);
+        let data = CompactData::new(
+            fixture_activities(),
+            &attributions,
+            &[],
+            None,
+            vec![],
+            &BTreeMap::new(),
+        );
+        let packed = MsgPack::new(&data, Encoding::Identity).unwrap();
+        let data: CompactData = rmp_serde::from_slice(&packed.body).unwrap();
+        let geojson::GeoJson::FeatureCollection(fc) =
+            geo::to_geojson(fixture_activities(), &attributions)
+        else {
+            panic!("expected a feature collection");
+        };
+
+        assert_eq!(data.activities.len(), fc.features.len());
+        for (compact, feature) in data.activities.iter().zip(fc.features) {
+            let property = |name: &str| feature.property(name).cloned().unwrap();
+            assert_eq!(property("id"), compact.id);
+            assert_eq!(property("name"), compact.name.as_str());
+            assert_eq!(property("distance"), compact.distance);
+            assert_eq!(
+                property("start_date"),
+                serde_json::to_value(
+                    chrono::DateTime::from_timestamp(compact.start_date, 0).unwrap()
+                )
+                .unwrap()
+            );
+            assert_eq!(
+                property("sport_type"),
+                serde_json::to_value(&compact.sport_type).unwrap()
+            );
+            assert_eq!(property("new_cells"), compact.new_cells.unwrap());
+            assert_eq!(property("total_cells"), compact.total_cells.unwrap());
+
+            let line = compact
+                .polyline
+                .as_ref()
+                .map(|p| polyline::decode_polyline(p, 5).unwrap());
+            let geometry = feature
+                .geometry
+                .map(|g| ::geo::LineString::try_from(g.value).unwrap());
+            assert_eq!(line, geometry);
+        }
+    }
+}
@@ -1,4 +1,6 @@
 use fernet::{Fernet, MultiFernet};
+use rand::distributions::{Alphanumeric, DistString};
+use sha2::{Digest, Sha256};
 use std::env;
 
 use crate::error;
@@ -45,6 +47,17 @@ impl Crypto {
     }
 }
 
+/// A new random token for share links
+pub fn new_token() -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), 32)
+}
+
+/// Tokens are only stored as their SHA-256, so a leaked db can't be used to view shares
+/// They're random enough that there's no need for a salt
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -61,6 +74,17 @@ mod tests {
         assert_eq!(want, got);
     }
 
+    #[test]
+    fn test_hash_token() {
+        let token = new_token();
+        assert_eq!(token.len(), 32);
+        assert_ne!(token, new_token());
+        let hashed = hash_token(&token);
+        assert_eq!(hashed.len(), 64);
+        assert_eq!(hashed, hash_token(&token));
+        assert_ne!(hashed, hash_token(&new_token()));
+    }
+
     #[test]
     fn test_decrypt_fallback() {
         let k1 = Fernet::generate_key();
@@ -1,16 +1,23 @@
+use std::collections::{HashMap, HashSet};
+
 use anyhow::Context;
 use diesel::connection::SimpleConnection;
 use diesel::prelude::*;
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use geo::Rect;
+use h3o::CellIndex;
 use log::{debug, warn};
 use rocket::{Build, Rocket};
 use rocket_sync_db_pools::database;
 
 use crate::crypto::Crypto;
 use crate::error;
-use crate::models::UserDb;
+use crate::models::{
+    Activity, ActivityDb, CellDb, GroupDb, GroupMemberDb, JobDb, NewGroupDb, NewJobDb,
+    NewPrivacyZoneDb, NewSyncJobDb, PrivacyZoneDb, ShareTokenDb, SyncJobDb, UserDb,
+};
 use crate::schema::users::dsl::*;
-use crate::{schema, strava};
+use crate::{h3, privacy, schema, strava};
 
 #[database("db")]
 pub struct Db(diesel::SqliteConnection);
@@ -20,6 +27,7 @@ pub struct Db(diesel::SqliteConnection);
 // tested without any outside setup of the database.
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 
+#[allow(clippy::result_large_err)]
 pub async fn migrate(rocket: Rocket<Build>) -> Result<Rocket<Build>, Rocket<Build>> {
     let db = Db::get_one(&rocket).await.expect("database connection");
     db.run(|conn| match conn.run_pending_migrations(MIGRATIONS) {
@@ -79,6 +87,720 @@ pub async fn get_user(db: &Db, user_id: i32) -> Result<UserDb, error::Error> {
     Ok(user)
 }
 
+/// Forget the user's Strava tokens, keeping everything else
+/// They'll get new ones the next time they log in
+pub async fn clear_tokens(db: &Db, user_id: i32) -> Result<usize, error::Error> {
+    db.run(move |c| {
+        diesel::update(users.find(user_id))
+            .set((refresh_token.eq(""), access_token.eq(""), expires_at.eq(0)))
+            .execute(c)
+            .with_context(|| "db::clear_tokens".to_string())
+            .map_err(error::Error::from)
+    })
+    .await
+}
+
+/// Store (or refresh) the given activities against this user
+pub async fn save_activities(
+    db: &Db,
+    user_id: i32,
+    activities: &[strava::ActivityResponse],
+) -> Result<usize, error::Error> {
+    let acts: Vec<ActivityDb> = activities
+        .iter()
+        .map(|a| ActivityDb::from_response(user_id, a))
+        .collect();
+    debug!("saving {} activities for user {}", acts.len(), user_id);
+    db.run(move |c| {
+        c.transaction::<_, diesel::result::Error, _>(|c| {
+            // Keep well under SQLite's limit on bound parameters, like save_cells
+            let mut count = 0;
+            for chunk in acts.chunks(250) {
+                count += diesel::replace_into(schema::activities::table)
+                    .values(chunk)
+                    .execute(c)?;
+            }
+            Ok(count)
+        })
+        .with_context(|| "db::save_activities".to_string())
+        .map_err(error::Error::from)
+    })
+    .await
+}
+
+/// Get all stored activities for this user, oldest first
+pub async fn get_activities(db: &Db, user_id: i32) -> Result<Vec<Activity>, error::Error> {
+    let acts = db
+        .run(move |c| {
+            schema::activities::table
+                .filter(schema::activities::user_id.eq(user_id))
+                .order(schema::activities::start_date.asc())
+                .select(ActivityDb::as_select())
+                .load(c)
+                .with_context(|| "db::get_activities".to_string())
+                .map_err(error::Error::from)
+        })
+        .await?;
+    Ok(acts.into_iter().map(Activity::from_db).collect())
+}
+
+/// Get this user's stored activities whose line might cross `bounds`, oldest first
+/// Activities stored before their bbox was kept are always included
+pub async fn get_activities_within(
+    db: &Db,
+    user_id: i32,
+    bounds: Rect,
+) -> Result<Vec<Activity>, error::Error> {
+    let acts = db
+        .run(move |c| {
+            schema::activities::table
+                .filter(schema::activities::user_id.eq(user_id))
+                .filter(schema::activities::summary_polyline.is_not_null())
+                .filter(
+                    schema::activities::min_lng
+                        .is_null()
+                        .or(schema::activities::min_lng
+                            .le(bounds.max().x)
+                            .and(schema::activities::max_lng.ge(bounds.min().x))
+                            .and(schema::activities::min_lat.le(bounds.max().y))
+                            .and(schema::activities::max_lat.ge(bounds.min().y))),
+                )
+                .order(schema::activities::start_date.asc())
+                .select(ActivityDb::as_select())
+                .load(c)
+                .with_context(|| "db::get_activities_within".to_string())
+                .map_err(error::Error::from)
+        })
+        .await?;
+    Ok(acts.into_iter().map(Activity::from_db).collect())
+}
+
+/// Start time of the user's latest stored activity, as a Unix timestamp
+pub async fn get_latest_start_date(db: &Db, user_id: i32) -> Result<Option<i64>, error::Error> {
+    db.run(move |c| {
+        schema::activities::table
+            .filter(schema::activities::user_id.eq(user_id))
+            .select(diesel::dsl::max(schema::activities::start_date))
+            .first(c)
+            .with_context(|| "db::get_latest_start_date".to_string())
+            .map_err(error::Error::from)
+    })
+    .await
+}
+
+/// Users who are still connected to Strava and have done an activity since `since`
+pub async fn get_active_users(db: &Db, since: i64) -> Result<Vec<UserDb>, error::Error> {
+    let active = db
+        .run(move |c| {
+            users
+                .inner_join(schema::activities::table)
+                .filter(refresh_token.ne(""))
+                .filter(schema::activities::start_date.ge(since))
+                .select(UserDb::as_select())
+                .distinct()
+                .load(c)
+                .with_context(|| "db::get_active_users".to_string())
+                .map_err(error::Error::from)
+        })
+        .await?;
+    let crypto = Crypto::default();
+    Ok(active
+        .into_iter()
+        .map(|user| UserDb {
+            refresh_token: crypto.decrypt_fallback(&user.refresh_token),
+            ..user
+        })
+        .collect())
+}
+
+pub async fn save_sync_job(db: &Db, job: NewSyncJobDb) -> Result<usize, error::Error> {
+    db.run(move |c| {
+        diesel::insert_into(schema::sync_jobs::table)
+            .values(&job)
+            .execute(c)
+            .with_context(|| "db::save_sync_job".to_string())
+            .map_err(error::Error::from)
+    })
+    .await
+}
+
+pub async fn get_sync_jobs(db: &Db, user_id: i32) -> Result<Vec<SyncJobDb>, error::Error> {
+    db.run(move |c| {
+        schema::sync_jobs::table
+            .filter(schema::sync_jobs::user_id.eq(user_id))
+            .order(schema::sync_jobs::id.asc())
+            .select(SyncJobDb::as_select())
+            .load(c)
+            .with_context(|| "db::get_sync_jobs".to_string())
+            .map_err(error::Error::from)
+    })
+    .await
+}
+
+/// Queue a full sync for this user, unless one is already queued or running
+/// Either way, returns the job that will do it
+pub async fn enqueue_job(db: &Db, user_id: i32, now: i64) -> Result<JobDb, error::Error> {
+    db.run(move |c| {
+        c.transaction::<_, diesel::result::Error, _>(|c| {
+            use schema::jobs;
+            let pending: Option<JobDb> = jobs::table
+                .filter(jobs::user_id.eq(user_id))
+                .filter(jobs::status.eq_any(["queued", "running"]))
+                .select(JobDb::as_select())
+                .first(c)
+                .optional()?;
+            if let Some(job) = pending {
+                return Ok(job);
+            }
+            diesel::insert_into(jobs::table)
+                .values(NewJobDb {
+                    user_id,
+                    status: "queued".to_string(),
+                    created_at: now,
+                    updated_at: now,
+                })
+                .execute(c)?;
+            jobs::table
+                .filter(jobs::user_id.eq(user_id))
+                .order(jobs::id.desc())
+                .select(JobDb::as_select())
+                .first(c)
+        })
+        .with_context(|| "db::enqueue_job".to_string())
+        .map_err(error::Error::from)
+    })
+    .await
+}
+
+/// Whether a full-history sync has ever finished for this user
+pub async fn has_done_job(db: &Db, user_id: i32) -> Result<bool, error::Error> {
+    db.run(move |c| {
+        use schema::jobs;
+        let done = jobs::table
+            .filter(jobs::user_id.eq(user_id))
+            .filter(jobs::status.eq("done"));
+        diesel::select(diesel::dsl::exists(done))
+            .get_result(c)
+            .with_context(|| "db::has_done_job".to_string())
+            .map_err(error::Error::from)
+    })
+    .await
+}
+
+/// Take the oldest queued job (if any) and mark it as running
+pub async fn claim_job(db: &Db, now: i64) -> Result<Option<JobDb>, error::Error> {
+    db.run(move |c| {
+        c.transaction::<_, diesel::result::Error, _>(|c| {
+            use schema::jobs;
+            let next: Option<i32> = jobs::table
+                .filter(jobs::status.eq("queued"))
+                .order(jobs::id.asc())
+                .select(jobs::id)
+                .first(c)
+                .optional()?;
+            let Some(job_id) = next else {
+                return Ok(None);
+            };
+            diesel::update(jobs::table.find(job_id))
+                .set((
+                    jobs::status.eq("running"),
+                    jobs::attempts.eq(jobs::attempts + 1),
+                    jobs::updated_at.eq(now),
+                ))
+                .execute(c)?;
+            jobs::table
+                .find(job_id)
+                .select(JobDb::as_select())
+                .first(c)
+                .optional()
+        })
+        .with_context(|| "db::claim_job".to_string())
+        .map_err(error::Error::from)
+    })
+    .await
+}
+
+pub async fn update_job_progress(
+    db: &Db,
+    job_id: i32,
+    fetched: i32,
+    total: Option<i32>,
+    now: i64,
+) -> Result<usize, error::Error> {
+    db.run(move |c| {
+        use schema::jobs;
+        diesel::update(jobs::table.find(job_id))
+            .set((
+                jobs::fetched.eq(fetched),
+                jobs::total.eq(total),
+                jobs::updated_at.eq(now),
+            ))
+            .execute(c)
+            .with_context(|| "db::update_job_progress".to_string())
+            .map_err(error::Error::from)
+    })
+    .await
+}
+
+/// Whether the job is still in the queue, as deleting the user takes their jobs with them
+pub async fn job_exists(db: &Db, job_id: i32) -> Result<bool, error::Error> {
+    db.run(move |c| {
+        use schema::jobs;
+        diesel::select(diesel::dsl::exists(jobs::table.find(job_id)))
+            .get_result(c)
+            .with_context(|| "db::job_exists".to_string())
+            .map_err(error::Error::from)
+    })
+    .await
+}
+
+/// Set the job's status once it's done, failed, or going back in the queue
+pub async fn finish_job(
+    db: &Db,
+    job_id: i32,
+    status: &'static str,
+    last_error: Option<String>,
+    now: i64,
+) -> Result<usize, error::Error> {
+    db.run(move |c| {
+        use schema::jobs;
+        diesel::update(jobs::table.find(job_id))
+            .set((
+                jobs::status.eq(status),
+                jobs::last_error.eq(last_error),
+                jobs::updated_at.eq(now),
+            ))
+            .execute(c)
+            .with_context(|| "db::finish_job".to_string())
+            .map_err(error::Error::from)
+    })
+    .await
+}
+
+/// Put back any jobs that were still running when the server stopped
+pub async fn requeue_running_jobs(db: &Db) -> Result<usize, error::Error> {
+    db.run(move |c| {
+        use schema::jobs;
+        diesel::update(jobs::table.filter(jobs::status.eq("running")))
+            .set(jobs::status.eq("queued"))
+            .execute(c)
+            .with_context(|| "db::requeue_running_jobs".to_string())
+            .map_err(error::Error::from)
+    })
+    .await
+}
+
+pub async fn get_jobs(db: &Db, user_id: i32) -> Result<Vec<JobDb>, error::Error> {
+    db.run(move |c| {
+        schema::jobs::table
+            .filter(schema::jobs::user_id.eq(user_id))
+            .order(schema::jobs::id.asc())
+            .select(JobDb::as_select())
+            .load(c)
+            .with_context(|| "db::get_jobs".to_string())
+            .map_err(error::Error::from)
+    })
+    .await
+}
+
+pub async fn get_latest_job(db: &Db, user_id: i32) -> Result<Option<JobDb>, error::Error> {
+    db.run(move |c| {
+        schema::jobs::table
+            .filter(schema::jobs::user_id.eq(user_id))
+            .order(schema::jobs::id.desc())
+            .select(JobDb::as_select())
+            .first(c)
+            .optional()
+            .with_context(|| "db::get_latest_job".to_string())
+            .map_err(error::Error::from)
+    })
+    .await
+}
+
+/// When each user's last sync attempt finished
+pub async fn get_last_synced(db: &Db) -> Result<HashMap<i32, i64>, error::Error> {
+    let last: Vec<(i32, Option<i64>)> = db
+        .run(move |c| {
+            schema::sync_jobs::table
+                .filter(schema::sync_jobs::kind.eq("sync"))
+                .group_by(schema::sync_jobs::user_id)
+                .select((
+                    schema::sync_jobs::user_id,
+                    diesel::dsl::max(schema::sync_jobs::finished_at),
+                ))
+                .load(c)
+                .with_context(|| "db::get_last_synced".to_string())
+                .map_err(error::Error::from)
+        })
+        .await?;
+    Ok(last
+        .into_iter()
+        .filter_map(|(user, finished)| Some((user, finished?)))
+        .collect())
+}
+
+/// Recompute this user's cells from all their stored activities
+/// leaving out any in privacy zones that shouldn't count
+pub async fn update_cells(db: &Db, user_id: i32) -> Result<usize, error::Error> {
+    let activities = get_activities(db, user_id).await?;
+    let zones: Vec<privacy::Zone> = get_privacy_zones(db, user_id)
+        .await?
+        .iter()
+        .map(privacy::Zone::from)
+        .collect();
+    let visits = h3::visits(&activities);
+    let visible: HashSet<CellIndex> =
+        privacy::mask_cells(visits.iter().map(|v| v.cell).collect(), &zones)
+            .into_iter()
+            .collect();
+    let cells: Vec<CellDb> = visits
+        .iter()
+        .filter(|v| visible.contains(&v.cell))
+        .map(|v| CellDb::from_visit(user_id, v))
+        .collect();
+    save_cells(db, user_id, cells).await
+}
+
+/// Replace this user's stored cells
+pub async fn save_cells(db: &Db, user_id: i32, cells: Vec<CellDb>) -> Result<usize, error::Error> {
+    debug!("saving {} cells for user {}", cells.len(), user_id);
+    db.run(move |c| {
+        c.transaction::<_, diesel::result::Error, _>(|c| {
+            diesel::delete(schema::cells::table.filter(schema::cells::user_id.eq(user_id)))
+                .execute(c)?;
+            // Keep well under SQLite's limit on bound parameters
+            let mut count = 0;
+            for chunk in cells.chunks(1000) {
+                count += diesel::insert_into(schema::cells::table)
+                    .values(chunk)
+                    .execute(c)?;
+            }
+            Ok(count)
+        })
+        .with_context(|| "db::save_cells".to_string())
+        .map_err(error::Error::from)
+    })
+    .await
+}
+
+/// This user's stored cells within any of these ranges (see h3::ranges_within)
+pub async fn get_cells_in(
+    db: &Db,
+    user_id: i32,
+    ranges: Vec<(CellIndex, CellIndex)>,
+) -> Result<Vec<CellDb>, error::Error> {
+    db.run(move |c| {
+        c.transaction::<_, diesel::result::Error, _>(|c| {
+            use schema::cells;
+            let mut found = Vec::new();
+            for (first, last) in ranges {
+                let first = u64::from(first) as i64;
+                let last = u64::from(last) as i64;
+                found.extend(
+                    cells::table
+                        .filter(cells::user_id.eq(user_id))
+                        .filter(cells::cell.between(first, last))
+                        .select(CellDb::as_select())
+                        .load(c)?,
+                );
+            }
+            Ok(found)
+        })
+        .with_context(|| "db::get_cells_in".to_string())
+        .map_err(error::Error::from)
+    })
+    .await
+}
+
+pub async fn get_cells(db: &Db, user_id: i32) -> Result<Vec<CellDb>, error::Error> {
+    db.run(move |c| {
+        schema::cells::table
+            .filter(schema::cells::user_id.eq(user_id))
+            .select(CellDb::as_select())
+            .load(c)
+            .with_context(|| "db::get_cells".to_string())
+            .map_err(error::Error::from)
+    })
+    .await
+}
+
+pub async fn get_privacy_zones(db: &Db, user_id: i32) -> Result<Vec<PrivacyZoneDb>, error::Error> {
+    db.run(move |c| {
+        schema::privacy_zones::table
+            .filter(schema::privacy_zones::user_id.eq(user_id))
+            .order(schema::privacy_zones::id.asc())
+            .select(PrivacyZoneDb::as_select())
+            .load(c)
+            .with_context(|| "db::get_privacy_zones".to_string())
+            .map_err(error::Error::from)
+    })
+    .await
+}
+
+pub async fn save_privacy_zone(db: &Db, zone: NewPrivacyZoneDb) -> Result<usize, error::Error> {
+    db.run(move |c| {
+        diesel::insert_into(schema::privacy_zones::table)
+            .values(&zone)
+            .execute(c)
+            .with_context(|| "db::save_privacy_zone".to_string())
+            .map_err(error::Error::from)
+    })
+    .await
+}
+
+/// Only deletes the zone if it belongs to this user
+pub async fn delete_privacy_zone(
+    db: &Db,
+    user_id: i32,
+    zone_id: i32,
+) -> Result<usize, error::Error> {
+    db.run(move |c| {
+        diesel::delete(
+            schema::privacy_zones::table
+                .filter(schema::privacy_zones::id.eq(zone_id))
+                .filter(schema::privacy_zones::user_id.eq(user_id)),
+        )
+        .execute(c)
+        .with_context(|| "db::delete_privacy_zone".to_string())
+        .map_err(error::Error::from)
+    })
+    .await
+}
+
+/// Set the user's share token, replacing (and so revoking) any old one
+pub async fn save_share_token(db: &Db, token: ShareTokenDb) -> Result<usize, error::Error> {
+    db.run(move |c| {
+        diesel::replace_into(schema::share_tokens::table)
+            .values(&token)
+            .execute(c)
+            .with_context(|| "db::save_share_token".to_string())
+            .map_err(error::Error::from)
+    })
+    .await
+}
+
+pub async fn delete_share_token(db: &Db, user_id: i32) -> Result<usize, error::Error> {
+    db.run(move |c| {
+        diesel::delete(schema::share_tokens::table.find(user_id))
+            .execute(c)
+            .with_context(|| "db::delete_share_token".to_string())
+            .map_err(error::Error::from)
+    })
+    .await
+}
+
+pub async fn get_share_token(db: &Db, user_id: i32) -> Result<Option<ShareTokenDb>, error::Error> {
+    db.run(move |c| {
+        schema::share_tokens::table
+            .find(user_id)
+            .select(ShareTokenDb::as_select())
+            .first(c)
+            .optional()
+            .with_context(|| "db::get_share_token".to_string())
+            .map_err(error::Error::from)
+    })
+    .await
+}
+
+/// Which user (if any) this hashed share token belongs to
+pub async fn get_share_user(db: &Db, token_hash: String) -> Result<Option<i32>, error::Error> {
+    db.run(move |c| {
+        schema::share_tokens::table
+            .filter(schema::share_tokens::token_hash.eq(token_hash))
+            .select(schema::share_tokens::user_id)
+            .first(c)
+            .optional()
+            .with_context(|| "db::get_share_user".to_string())
+            .map_err(error::Error::from)
+    })
+    .await
+}
+
+/// Create a group, with its owner as the first member
+pub async fn create_group(db: &Db, group: NewGroupDb) -> Result<GroupDb, error::Error> {
+    db.run(move |c| {
+        c.transaction::<_, diesel::result::Error, _>(|c| {
+            diesel::insert_into(schema::groups::table)
+                .values(&group)
+                .execute(c)?;
+            let created: GroupDb = schema::groups::table
+                .filter(schema::groups::invite_code.eq(&group.invite_code))
+                .select(GroupDb::as_select())
+                .first(c)?;
+            diesel::insert_into(schema::group_members::table)
+                .values(GroupMemberDb {
+                    group_id: created.id,
+                    user_id: group.owner_id,
+                    joined_at: group.created_at,
+                })
+                .execute(c)?;
+            Ok(created)
+        })
+        .with_context(|| "db::create_group".to_string())
+        .map_err(error::Error::from)
+    })
+    .await
+}
+
+/// Add this user to the group with this invite code (if there is one)
+pub async fn join_group(
+    db: &Db,
+    user_id: i32,
+    code: String,
+    joined_at: i64,
+) -> Result<Option<GroupDb>, error::Error> {
+    db.run(move |c| {
+        c.transaction::<_, diesel::result::Error, _>(|c| {
+            let group: Option<GroupDb> = schema::groups::table
+                .filter(schema::groups::invite_code.eq(&code))
+                .select(GroupDb::as_select())
+                .first(c)
+                .optional()?;
+            if let Some(group) = &group {
+                diesel::insert_or_ignore_into(schema::group_members::table)
+                    .values(GroupMemberDb {
+                        group_id: group.id,
+                        user_id,
+                        joined_at,
+                    })
+                    .execute(c)?;
+            }
+            Ok(group)
+        })
+        .with_context(|| "db::join_group".to_string())
+        .map_err(error::Error::from)
+    })
+    .await
+}
+
+/// If the owner leaves, the group goes to whoever's been in it longest,
+/// or is deleted when there's nobody left
+pub async fn leave_group(db: &Db, user_id: i32, group_id: i32) -> Result<usize, error::Error> {
+    db.run(move |c| {
+        c.transaction::<_, diesel::result::Error, _>(|c| {
+            let left = diesel::delete(
+                schema::group_members::table
+                    .filter(schema::group_members::group_id.eq(group_id))
+                    .filter(schema::group_members::user_id.eq(user_id)),
+            )
+            .execute(c)?;
+            let owned = schema::groups::table
+                .find(group_id)
+                .filter(schema::groups::owner_id.eq(user_id));
+            if left > 0 && diesel::select(diesel::dsl::exists(owned)).get_result(c)? {
+                hand_over_group(c, group_id)?;
+            }
+            Ok(left)
+        })
+        .with_context(|| "db::leave_group".to_string())
+        .map_err(error::Error::from)
+    })
+    .await
+}
+
+/// Give the group to its longest-standing member, or delete it if it has none
+/// For when the owner has already been taken out of the members
+fn hand_over_group(c: &mut diesel::SqliteConnection, group_id: i32) -> QueryResult<usize> {
+    use schema::{group_members, groups};
+    let next: Option<i32> = group_members::table
+        .filter(group_members::group_id.eq(group_id))
+        .order(group_members::joined_at.asc())
+        .select(group_members::user_id)
+        .first(c)
+        .optional()?;
+    match next {
+        Some(next) => diesel::update(groups::table.find(group_id))
+            .set(groups::owner_id.eq(next))
+            .execute(c),
+        None => diesel::delete(groups::table.find(group_id)).execute(c),
+    }
+}
+
+/// The group, if it exists and this user is a member of it
+pub async fn get_group(
+    db: &Db,
+    user_id: i32,
+    group_id: i32,
+) -> Result<Option<GroupDb>, error::Error> {
+    db.run(move |c| {
+        schema::groups::table
+            .inner_join(schema::group_members::table)
+            .filter(schema::groups::id.eq(group_id))
+            .filter(schema::group_members::user_id.eq(user_id))
+            .select(GroupDb::as_select())
+            .first(c)
+            .optional()
+            .with_context(|| "db::get_group".to_string())
+            .map_err(error::Error::from)
+    })
+    .await
+}
+
+/// Every group this user is a member of
+pub async fn get_user_groups(db: &Db, user_id: i32) -> Result<Vec<GroupDb>, error::Error> {
+    db.run(move |c| {
+        schema::groups::table
+            .inner_join(schema::group_members::table)
+            .filter(schema::group_members::user_id.eq(user_id))
+            .order(schema::groups::id.asc())
+            .select(GroupDb::as_select())
+            .load(c)
+            .with_context(|| "db::get_user_groups".to_string())
+            .map_err(error::Error::from)
+    })
+    .await
+}
+
+/// User ids of everyone in the group, in the order they joined
+pub async fn get_group_members(db: &Db, group_id: i32) -> Result<Vec<i32>, error::Error> {
+    db.run(move |c| {
+        schema::group_members::table
+            .filter(schema::group_members::group_id.eq(group_id))
+            .order(schema::group_members::joined_at.asc())
+            .select(schema::group_members::user_id)
+            .load(c)
+            .with_context(|| "db::get_group_members".to_string())
+            .map_err(error::Error::from)
+    })
+    .await
+}
+
+/// Remove the user and everything stored about them
+/// Groups they own pass to the longest-standing remaining member,
+/// or are deleted if nobody is left
+pub async fn delete_user(db: &Db, user_id: i32) -> Result<usize, error::Error> {
+    db.run(move |c| {
+        c.transaction::<_, diesel::result::Error, _>(|c| {
+            use schema::{
+                activities, cells, group_members, groups, jobs, privacy_zones, share_tokens,
+                sync_jobs,
+            };
+            diesel::delete(activities::table.filter(activities::user_id.eq(user_id))).execute(c)?;
+            diesel::delete(cells::table.filter(cells::user_id.eq(user_id))).execute(c)?;
+            diesel::delete(privacy_zones::table.filter(privacy_zones::user_id.eq(user_id)))
+                .execute(c)?;
+            diesel::delete(share_tokens::table.find(user_id)).execute(c)?;
+            diesel::delete(sync_jobs::table.filter(sync_jobs::user_id.eq(user_id))).execute(c)?;
+            diesel::delete(jobs::table.filter(jobs::user_id.eq(user_id))).execute(c)?;
+            diesel::delete(group_members::table.filter(group_members::user_id.eq(user_id)))
+                .execute(c)?;
+
+            let owned: Vec<i32> = groups::table
+                .filter(groups::owner_id.eq(user_id))
+                .select(groups::id)
+                .load(c)?;
+            for group_id in owned {
+                hand_over_group(c, group_id)?;
+            }
+
+            diesel::delete(users.find(user_id)).execute(c)
+        })
+        .with_context(|| "db::delete_user".to_string())
+        .map_err(error::Error::from)
+    })
+    .await
+}
+
 /// These pragmas hopefully prevent the DB from locking up
 /// Source: https://github.com/the-lean-crate/criner/issues/1
 pub async fn prep_db(db: &Db) -> Result<(), error::Error> {
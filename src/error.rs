@@ -19,6 +19,22 @@ where
     }
 }
 
+impl Error {
+    /// The message with any request URL taken out, as Strava token URLs
+    /// have the client secret in them, for storing where users can see it
+    pub fn redacted(&self) -> String {
+        let msg = self.0.to_string();
+        match self
+            .0
+            .downcast_ref::<reqwest::Error>()
+            .and_then(|e| e.url())
+        {
+            Some(url) => msg.replace(url.as_str(), "<redacted>"),
+            None => msg,
+        }
+    }
+}
+
 impl<'r, 'o: 'r> Responder<'r, 'o> for Error {
     fn respond_to(self, req: &'r Request<'_>) -> response::Result<'o> {
         let mut msg = format!("Handling error: {}", self.0);
@@ -0,0 +1,95 @@
+use rocket::tokio::sync::broadcast;
+use serde::Serialize;
+
+/// How many events can be waiting before slow listeners start missing them
+const CAPACITY: usize = 1024;
+
+/// Something that happened during a sync, for the `/events` stream
+/// Serialized without the variant, as that goes in the SSE `event:` field instead
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Event {
+    /// Activities fetched so far, out of roughly `total`
+    Progress {
+        fetched: usize,
+        total: Option<usize>,
+    },
+    /// Cells (as hex) visited for the first time by the activities just fetched
+    Cells { cells: Vec<String> },
+    /// The sync finished, so it's worth reloading everything
+    Done { fetched: usize },
+    /// The sync went wrong (it might be retried)
+    Failed { message: String },
+}
+
+impl Event {
+    /// Name for the SSE `event:` field, so the frontend can listen for each kind
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Progress { .. } => "progress",
+            Event::Cells { .. } => "cells",
+            Event::Done { .. } => "done",
+            Event::Failed { .. } => "failed",
+        }
+    }
+}
+
+/// Broadcasts every user's events to everyone listening
+/// Each `/events` stream only passes on the ones for its own user
+#[derive(Debug, Clone)]
+pub struct Events {
+    tx: broadcast::Sender<(i32, Event)>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        let (tx, _) = broadcast::channel(CAPACITY);
+        Events { tx }
+    }
+}
+
+impl Events {
+    /// Fine to call with nobody listening, the event just goes nowhere
+    pub fn send(&self, user_id: i32, event: Event) {
+        let _ = self.tx.send((user_id, event));
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<(i32, Event)> {
+        self.tx.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events() {
+        let events = Events::default();
+        // Nobody listening yet
+        events.send(1, Event::Done { fetched: 0 });
+
+        let mut rx = events.subscribe();
+        let progress = Event::Progress {
+            fetched: 200,
+            total: Some(3000),
+        };
+        events.send(1, progress.clone());
+        events.send(2, Event::Done { fetched: 5 });
+        assert_eq!(rx.try_recv().unwrap(), (1, progress));
+        assert_eq!(rx.try_recv().unwrap(), (2, Event::Done { fetched: 5 }));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_event_json() {
+        let event = Event::Cells {
+            cells: vec!["89195da49b7ffff".to_string()],
+        };
+        assert_eq!(event.name(), "cells");
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"cells":["89195da49b7ffff"]}"#
+        );
+    }
+}
@@ -0,0 +1,357 @@
+use std::collections::HashMap;
+use std::io::Write;
+
+use chrono::{DateTime, Utc};
+use gpx::{Gpx, GpxVersion, Track, TrackSegment, Waypoint};
+use kml::types::{LineStyle, Placemark, Style};
+use kml::{Kml, KmlDocument, KmlVersion, KmlWriter};
+use serde::Serialize;
+use time::OffsetDateTime;
+use zip::write::SimpleFileOptions;
+use zip::ZipWriter;
+
+use crate::error;
+use crate::models::{
+    Activity, CellDb, GroupDb, JobDb, PrivacyZoneDb, ShareTokenDb, SyncJobDb, UserDb,
+};
+use crate::sport::{Category, SportType};
+
+/// KML styles as (id, aabbggrr colour), matching the line colours on the map
+const STYLES: [(&str, &str); 5] = [
+    ("ride", "ffa34e98"),  // lilac
+    ("run", "ff007fff"),   // orange
+    ("walk", "ff4aaf4d"),  // green
+    ("swim", "ffb87e37"),  // blue
+    ("other", "ff595959"), // dark grey
+];
+
+/// Which of the STYLES to use for a given sport
+fn style_id(sport_type: &SportType) -> &'static str {
+    match sport_type.category() {
+        Category::Ride => "ride",
+        Category::Run => "run",
+        Category::Walk => "walk",
+        Category::Swim => "swim",
+        Category::Water | Category::Other => "other",
+    }
+}
+
+/// Write activities as GPX, with one track per activity
+/// Activities without a polyline are skipped
+pub fn to_gpx(activities: &[Activity]) -> Result<String, error::Error> {
+    let mut tracks: Vec<Track> = Vec::with_capacity(activities.len());
+    for activity in activities {
+        let Some(ls) = &activity.linestring else {
+            continue;
+        };
+        // Summary polylines have no timestamps, so the start time
+        // goes on the first point of the track
+        let time = OffsetDateTime::from_unix_timestamp(activity.start_date.timestamp())?;
+        let mut segment = TrackSegment::new();
+        for (i, point) in ls.points().enumerate() {
+            let mut waypoint = Waypoint::new(point);
+            if i == 0 {
+                waypoint.time = Some(time.into());
+            }
+            segment.points.push(waypoint);
+        }
+        let mut track = Track::new();
+        track.name = Some(activity.name.clone());
+        track.type_ = Some(activity.sport_type.to_string());
+        track.segments.push(segment);
+        tracks.push(track);
+    }
+    let gpx = Gpx {
+        version: GpxVersion::Gpx11,
+        creator: Some("hexy".to_string()),
+        tracks,
+        ..Default::default()
+    };
+    let mut buf = Vec::new();
+    gpx::write(&gpx, &mut buf)?;
+    Ok(String::from_utf8(buf)?)
+}
+
+/// Write activities as KML, with one placemark per activity
+/// styled by sport type
+pub fn to_kml(activities: &[Activity]) -> Result<String, error::Error> {
+    let mut elements: Vec<Kml> = STYLES
+        .iter()
+        .map(|(id, color)| {
+            Kml::Style(Style {
+                id: Some(id.to_string()),
+                line: Some(LineStyle {
+                    color: color.to_string(),
+                    width: 3.0,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+        })
+        .collect();
+    for activity in activities {
+        let Some(ls) = &activity.linestring else {
+            continue;
+        };
+        let placemark = Placemark {
+            name: Some(activity.name.clone()),
+            description: Some(format!(
+                "{} on {}",
+                activity.sport_type,
+                activity.start_date.to_rfc3339()
+            )),
+            geometry: Some(kml::types::Geometry::LineString(ls.clone().into())),
+            style_url: Some(format!("#{}", style_id(&activity.sport_type))),
+            children: vec![kml::types::Element {
+                name: "TimeStamp".to_string(),
+                children: vec![kml::types::Element {
+                    name: "when".to_string(),
+                    content: Some(activity.start_date.to_rfc3339()),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        elements.push(Kml::Placemark(placemark));
+    }
+    let doc = Kml::KmlDocument(KmlDocument {
+        version: KmlVersion::V22,
+        attrs: HashMap::from([(
+            "xmlns".to_string(),
+            "http://www.opengis.net/kml/2.2".to_string(),
+        )]),
+        elements: vec![Kml::Document {
+            attrs: HashMap::new(),
+            elements,
+        }],
+    });
+    let mut buf = Vec::new();
+    KmlWriter::<_, f64>::from_writer(&mut buf).write(&doc)?;
+    Ok(String::from_utf8(buf)?)
+}
+
+/// Everything stored about a user, for `GET /account/export`
+pub struct AccountData {
+    pub user: UserDb,
+    pub activities: Vec<Activity>,
+    pub cells: Vec<CellDb>,
+    pub groups: Vec<GroupDb>,
+    pub privacy_zones: Vec<PrivacyZoneDb>,
+    pub share: Option<ShareTokenDb>,
+    pub sync_jobs: Vec<SyncJobDb>,
+    pub jobs: Vec<JobDb>,
+}
+
+/// The user row, without the Strava tokens (or the share token hash)
+/// as they're credentials rather than data about the user
+#[derive(Serialize)]
+struct Account {
+    id: i32,
+    token_expires_at: Option<DateTime<Utc>>,
+    sharing_since: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct Cell {
+    cell: String,
+    first_visited: Option<DateTime<Utc>>,
+    visits: i32,
+}
+
+impl AccountData {
+    /// A zip of JSON files, one per table, plus the activities as GeoJSON and GPX
+    pub fn to_zip(self) -> Result<Vec<u8>, error::Error> {
+        let account = Account {
+            id: self.user.id,
+            token_expires_at: DateTime::from_timestamp(self.user.expires_at.into(), 0),
+            sharing_since: self
+                .share
+                .and_then(|s| DateTime::from_timestamp(s.created_at, 0)),
+        };
+        let cells: Vec<Cell> = self
+            .cells
+            .iter()
+            .map(|c| Cell {
+                cell: format!("{:x}", c.cell),
+                first_visited: DateTime::from_timestamp(c.first_visited, 0),
+                visits: c.visits,
+            })
+            .collect();
+        let gpx = to_gpx(&self.activities)?;
+        let geojson = crate::geo::to_geojson(self.activities, &[]).to_string();
+
+        let files = [
+            ("account.json", serde_json::to_string_pretty(&account)?),
+            ("activities.geojson", geojson),
+            ("activities.gpx", gpx),
+            ("cells.json", serde_json::to_string_pretty(&cells)?),
+            ("groups.json", serde_json::to_string_pretty(&self.groups)?),
+            ("jobs.json", serde_json::to_string_pretty(&self.jobs)?),
+            (
+                "privacy_zones.json",
+                serde_json::to_string_pretty(&self.privacy_zones)?,
+            ),
+            (
+                "sync_jobs.json",
+                serde_json::to_string_pretty(&self.sync_jobs)?,
+            ),
+        ];
+        let mut zip = ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for (name, body) in files {
+            zip.start_file(name, SimpleFileOptions::default())?;
+            zip.write_all(body.as_bytes())?;
+        }
+        Ok(zip.finish()?.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use chrono::{TimeZone, Utc};
+    use geo::{line_string, LineString};
+
+    use super::*;
+
+    fn activities() -> Vec<Activity> {
+        let make = |id: i64, sport_type: &str, linestring: Option<LineString>| Activity {
+            id,
+            name: format!("Activity {}", id),
+            distance: 1000.0,
+            moving_time: 600,
+            elapsed_time: 700,
+            start_date: Utc.with_ymd_and_hms(2024, 4, id as u32, 8, 30, 0).unwrap(),
+            kudos_count: 0,
+            average_speed: 2.5,
+            sport_type: SportType::from(sport_type),
+            linestring,
+        };
+        vec![
+            make(
+                1,
+                "Ride",
+                Some(line_string![(x: -0.1276, y: 51.5072), (x: -0.1301, y: 51.5104)]),
+            ),
+            make(2, "Swim", None),
+            make(
+                3,
+                "Hike",
+                Some(
+                    line_string![(x: -3.1883, y: 55.9533), (x: -3.1712, y: 55.9445), (x: -3.1615, y: 55.9441)],
+                ),
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_gpx_round_trip() {
+        let acts = activities();
+        let out = to_gpx(&acts).unwrap();
+        let got = gpx::read(out.as_bytes()).unwrap();
+
+        let want: Vec<&Activity> = acts.iter().filter(|a| a.linestring.is_some()).collect();
+        assert_eq!(got.tracks.len(), want.len());
+        for (track, activity) in got.tracks.iter().zip(want) {
+            assert_eq!(track.name.as_ref(), Some(&activity.name));
+            assert_eq!(track.type_, Some(activity.sport_type.to_string()));
+            assert_eq!(
+                &track.segments[0].linestring(),
+                activity.linestring.as_ref().unwrap()
+            );
+            let time: OffsetDateTime = track.segments[0].points[0].time.unwrap().into();
+            assert_eq!(time.unix_timestamp(), activity.start_date.timestamp());
+        }
+    }
+
+    #[test]
+    fn test_kml_round_trip() {
+        let acts = activities();
+        let out = to_kml(&acts).unwrap();
+        let Kml::KmlDocument(doc) = out.parse::<Kml>().unwrap() else {
+            panic!("expected a kml document");
+        };
+        let Kml::Document { elements, .. } = &doc.elements[0] else {
+            panic!("expected a document");
+        };
+        let placemarks: Vec<&Placemark> = elements
+            .iter()
+            .filter_map(|e| match e {
+                Kml::Placemark(p) => Some(p),
+                _ => None,
+            })
+            .collect();
+
+        let want: Vec<&Activity> = acts.iter().filter(|a| a.linestring.is_some()).collect();
+        assert_eq!(placemarks.len(), want.len());
+        for (placemark, activity) in placemarks.iter().zip(want) {
+            assert_eq!(placemark.name.as_ref(), Some(&activity.name));
+            let Some(kml::types::Geometry::LineString(ls)) = placemark.geometry.clone() else {
+                panic!("expected a linestring");
+            };
+            assert_eq!(&LineString::from(ls), activity.linestring.as_ref().unwrap());
+        }
+        assert_eq!(placemarks[0].style_url.as_deref(), Some("#ride"));
+        assert_eq!(placemarks[1].style_url.as_deref(), Some("#walk"));
+    }
+
+    #[test]
+    fn test_account_zip() {
+        let data = AccountData {
+            user: UserDb {
+                id: 7,
+                access_token: "access".to_string(),
+                refresh_token: "refresh".to_string(),
+                expires_at: 1_700_000_000,
+            },
+            activities: activities(),
+            cells: vec![CellDb {
+                user_id: 7,
+                cell: 0x89195da49b7ffff,
+                first_visited: 1_700_000_000,
+                visits: 2,
+            }],
+            groups: vec![],
+            privacy_zones: vec![],
+            share: None,
+            sync_jobs: vec![],
+            jobs: vec![],
+        };
+        let zip = data.to_zip().unwrap();
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(zip)).unwrap();
+        let mut names: Vec<&str> = archive.file_names().collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                "account.json",
+                "activities.geojson",
+                "activities.gpx",
+                "cells.json",
+                "groups.json",
+                "jobs.json",
+                "privacy_zones.json",
+                "sync_jobs.json"
+            ]
+        );
+
+        let mut account = String::new();
+        archive
+            .by_name("account.json")
+            .unwrap()
+            .read_to_string(&mut account)
+            .unwrap();
+        assert!(account.contains("\"id\": 7"));
+        assert!(!account.contains("access"));
+        assert!(!account.contains("refresh"));
+
+        let mut cells = String::new();
+        archive
+            .by_name("cells.json")
+            .unwrap()
+            .read_to_string(&mut cells)
+            .unwrap();
+        assert!(cells.contains("89195da49b7ffff"));
+    }
+}
@@ -1,10 +1,14 @@
+use std::cmp::Reverse;
 use std::collections::HashMap;
+use std::env;
 
-use dbscan;
-use geo::{Centroid, MultiPoint, Point};
+use geo::{
+    BoundingRect, Centroid, Coord, HaversineDistance, LineString, MultiPoint, Point, Rect, Simplify,
+};
 use geojson::{Feature, FeatureCollection, GeoJson, Geometry, Value};
 
-use crate::models::Activity;
+use crate::h3::Attribution;
+use crate::models::{Activity, Region};
 use crate::strava::ActivityResponse;
 
 /// Convert Strava responses to our Activity model, with the only real difference
@@ -18,13 +22,38 @@ pub fn decode_all(activities: Vec<ActivityResponse>) -> Vec<Activity> {
     acts
 }
 
+/// Tolerance (in degrees) of roughly one pixel at this zoom level
+/// Based on 256px tiles, so it's an overestimate away from the equator
+pub fn zoom_to_tolerance(zoom: f64) -> f64 {
+    360.0 / (256.0 * 2f64.powf(zoom))
+}
+
+/// Simplify activity lines (Ramer–Douglas–Peucker) to the given tolerance in degrees
+/// Use this before to_geojson to cut down on the response size
+pub fn simplify_all(activities: Vec<Activity>, tolerance: f64) -> Vec<Activity> {
+    activities
+        .into_iter()
+        .map(|a| Activity {
+            linestring: a.linestring.map(|ls| ls.simplify(&tolerance)),
+            ..a
+        })
+        .collect()
+}
+
 /// Convert Activities to GeoJSON with properties
 /// Only use this for final web response, as GeoJSON isn't
 /// useful for processing
-pub fn to_geojson(activities: Vec<Activity>) -> GeoJson {
+/// Activities with an Attribution also get `new_cells` and `total_cells`
+pub fn to_geojson(activities: Vec<Activity>, attributions: &[Attribution]) -> GeoJson {
+    let attributions: HashMap<i64, &Attribution> = attributions.iter().map(|a| (a.id, a)).collect();
     let mut features: Vec<Feature> = Vec::with_capacity(activities.len());
     for activity in activities {
-        let properties = activity.to_properties();
+        let mut properties = activity.to_properties();
+        if let (Some(props), Some(attribution)) = (&mut properties, attributions.get(&activity.id))
+        {
+            props.insert("new_cells".to_string(), attribution.new_cells.into());
+            props.insert("total_cells".to_string(), attribution.total_cells.into());
+        }
         let geometry: Option<Geometry> = activity.linestring.map(|ls| {
             let ls = ls.into_iter().map(|c| vec![c.x, c.y]).collect();
             Geometry::new(Value::LineString(ls))
@@ -46,48 +75,323 @@ pub fn to_geojson(activities: Vec<Activity>) -> GeoJson {
     fc.into()
 }
 
-/// Get a useful centroid to zoom the user to
-/// This function is a bit of a monster, could be probably be made much faster
-pub fn get_useful_centroid(activities: &[Activity]) -> Option<Point> {
-    // Get all activity centroids
-    let centroids: Vec<Vec<f64>> = activities
-        .iter()
-        .filter_map(|a| a.linestring.clone())
-        .filter_map(|l| l.centroid())
-        .map(|c| vec![c.x(), c.y()])
-        .collect();
+/// Settings for finding clusters of activities with DBSCAN
+pub struct ClusterConfig {
+    /// Max distance between activity centroids in a cluster, in metres
+    pub eps: f64,
+    /// Min number of activities to count as a cluster
+    pub min_points: usize,
+}
 
-    let model: dbscan::Model<f64> = dbscan::Model::new(0.1, 10);
-    let classifications = model.run(&centroids);
+impl Default for ClusterConfig {
+    /// Can be tweaked with CLUSTER_EPS and CLUSTER_MIN_POINTS
+    fn default() -> Self {
+        let eps = env::var("CLUSTER_EPS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(10_000.0);
+        let min_points = env::var("CLUSTER_MIN_POINTS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(10);
+        Self { eps, min_points }
+    }
+}
+
+/// Metres in a degree of latitude, on the same sphere as `HaversineDistance`
+const METRES_PER_DEGREE: f64 = 6_371_008.8 * std::f64::consts::PI / 180.0;
+
+/// Points sorted by latitude, so that finding a point's neighbours only means
+/// checking the ones within `eps` of its latitude rather than every point
+struct Neighbours<'a> {
+    points: &'a [Point],
+    eps: f64,
+    /// Indices into `points`, by latitude
+    order: Vec<usize>,
+    lats: Vec<f64>,
+}
 
-    // Count number in each group
-    let mut counts = HashMap::new();
-    for value in classifications.clone().into_iter() {
-        if let dbscan::Classification::Core(num) = value {
-            *counts.entry(num).or_insert(0) += 1;
+impl<'a> Neighbours<'a> {
+    fn new(points: &'a [Point], eps: f64) -> Self {
+        let mut order: Vec<usize> = (0..points.len()).collect();
+        order.sort_by(|a, b| points[*a].y().total_cmp(&points[*b].y()));
+        let lats = order.iter().map(|i| points[*i].y()).collect();
+        Neighbours {
+            points,
+            eps,
+            order,
+            lats,
         }
     }
 
-    // Find the Core value with the highest count
-    // Basicaly, the biggest group
-    let group_num = counts
-        .into_iter()
-        .max_by_key(|&(_, count)| count)
-        .map(|(size, _)| size)?;
+    /// Indices of the points closer than `eps` metres to this one, including itself
+    /// Anything further apart in latitude alone can't be, so it's never measured
+    fn of(&self, i: usize) -> Vec<usize> {
+        let point = self.points[i];
+        let band = self.eps / METRES_PER_DEGREE;
+        let start = self.lats.partition_point(|lat| *lat < point.y() - band);
+        let end = self.lats.partition_point(|lat| *lat <= point.y() + band);
+        self.order[start..end]
+            .iter()
+            .copied()
+            .filter(|j| point.haversine_distance(&self.points[*j]) < self.eps)
+            .collect()
+    }
+}
 
-    // Now get all the centroids that represent that group
-    let biggest_group: Vec<Point<f64>> = classifications
-        .iter()
-        .enumerate()
-        .filter_map(|(i, value)| match value {
-            dbscan::Classification::Core(num) if *num == group_num => {
-                Some(Point::new(centroids[i][0], centroids[i][1]))
+/// DBSCAN with haversine distances, returning which cluster (if any) each point is in
+fn cluster(points: &[Point], config: &ClusterConfig) -> Vec<Option<usize>> {
+    let neighbours = Neighbours::new(points, config.eps);
+    let mut clusters: Vec<Option<usize>> = vec![None; points.len()];
+    let mut visited = vec![false; points.len()];
+    let mut next = 0;
+    for i in 0..points.len() {
+        if visited[i] {
+            continue;
+        }
+        visited[i] = true;
+        let mut queue = vec![i];
+        let mut found = false;
+        while let Some(j) = queue.pop() {
+            let near = neighbours.of(j);
+            if near.len() < config.min_points {
+                continue;
             }
-            _ => None,
-        })
+            // j is a core point, and everything near it is at least an edge point
+            found = true;
+            clusters[j] = Some(next);
+            for k in near {
+                clusters[k].get_or_insert(next);
+                if !visited[k] {
+                    visited[k] = true;
+                    queue.push(k);
+                }
+            }
+        }
+        if found {
+            next += 1;
+        }
+    }
+    clusters
+}
+
+/// Centroid and bounding box of a group of activities
+fn to_region(lines: &[&LineString], centroids: Vec<Point>) -> Option<Region> {
+    let count = lines.len();
+    let centroid = MultiPoint::new(centroids).centroid()?;
+    let bbox = lines
+        .iter()
+        .filter_map(|l| l.bounding_rect())
+        .reduce(|a, b| {
+            let min = Coord {
+                x: a.min().x.min(b.min().x),
+                y: a.min().y.min(b.min().y),
+            };
+            let max = Coord {
+                x: a.max().x.max(b.max().x),
+                y: a.max().y.max(b.max().y),
+            };
+            Rect::new(min, max)
+        })?;
+    Some(Region {
+        centroid,
+        bbox,
+        count,
+    })
+}
+
+/// Find all the clusters of activities, biggest first
+/// so that users who travel get all their usual spots
+pub fn get_regions(activities: &[Activity], config: &ClusterConfig) -> Vec<Region> {
+    // Get all activity centroids, and keep the lines for the bbox
+    let (lines, centroids): (Vec<&LineString>, Vec<Point>) = activities
+        .iter()
+        .filter_map(|a| a.linestring.as_ref())
+        .filter_map(|l| l.centroid().map(|c| (l, c)))
+        .unzip();
+
+    // Gather the activities in each group
+    let mut groups: HashMap<usize, (Vec<&LineString>, Vec<Point>)> = HashMap::new();
+    for (i, num) in cluster(&centroids, config).into_iter().enumerate() {
+        if let Some(num) = num {
+            let group = groups.entry(num).or_default();
+            group.0.push(lines[i]);
+            group.1.push(centroids[i]);
+        }
+    }
+
+    // And get the centroid and bbox of each, biggest group first
+    let mut regions: Vec<Region> = groups
+        .into_values()
+        .filter_map(|(lines, centroids)| to_region(&lines, centroids))
         .collect();
+    regions.sort_by_key(|r| Reverse(r.count));
+    regions
+}
 
-    // And get the centroid of those points!
-    let biggest_group = MultiPoint::new(biggest_group);
-    biggest_group.centroid()
+/// Region around the most recent activity that has a line
+pub fn latest_region(activities: &[Activity]) -> Option<Region> {
+    let latest = activities
+        .iter()
+        .filter(|a| a.linestring.is_some())
+        .max_by_key(|a| a.start_date)?;
+    let line = latest.linestring.as_ref()?;
+    to_region(&[line], vec![line.centroid()?])
+}
+
+/// Get a useful area to zoom the user to
+/// This is the biggest cluster of activities, or the most recent
+/// activity if there aren't enough in any one place to make a cluster
+pub fn get_useful_centroid(activities: &[Activity], config: &ClusterConfig) -> Option<Region> {
+    get_regions(activities, config)
+        .into_iter()
+        .next()
+        .or_else(|| latest_region(activities))
+}
+
+/// Realistic-ish Strava responses to test against
+#[cfg(test)]
+pub(crate) fn fixture_activities() -> Vec<Activity> {
+    let raw = include_str!("../tests/fixtures/activities.json");
+    let activities: Vec<ActivityResponse> = serde_json::from_str(raw).unwrap();
+    decode_all(activities)
+}
+
+#[cfg(test)]
+mod tests {
+    use geo::Intersects;
+
+    use super::*;
+
+    fn geojson_size(activities: Vec<Activity>) -> usize {
+        serde_json::to_string(&to_geojson(activities, &[]))
+            .unwrap()
+            .len()
+    }
+
+    #[test]
+    fn test_simplify_all() {
+        let before = geojson_size(fixture_activities());
+        let mut last = before;
+        // At least this fraction of the bytes saved at each zoom, with some slack
+        // (on the fixtures it's roughly 39%, 89% and 97%)
+        for (zoom, min_saved) in [(16.0, 0.3), (12.0, 0.8), (8.0, 0.95)] {
+            let tolerance = zoom_to_tolerance(zoom);
+            let after = geojson_size(simplify_all(fixture_activities(), tolerance));
+            let saved = (before - after) as f64 / before as f64;
+            assert!(
+                saved >= min_saved,
+                "zoom {}: saved {:.0}% of {} bytes",
+                zoom,
+                100.0 * saved,
+                before
+            );
+            assert!(after < last);
+            last = after;
+        }
+    }
+
+    #[test]
+    fn test_useful_centroid() {
+        let config = ClusterConfig {
+            eps: 10_000.0,
+            min_points: 10,
+        };
+        let region = get_useful_centroid(&fixture_activities(), &config).unwrap();
+        let london = Point::new(-0.1276, 51.5072);
+        assert!(region.centroid.haversine_distance(&london) < 10_000.0);
+        assert!(region.count >= 10);
+        assert!(region.bbox.intersects(&london));
+    }
+
+    #[test]
+    fn test_get_regions() {
+        let config = ClusterConfig {
+            eps: 10_000.0,
+            min_points: 5,
+        };
+        let regions = get_regions(&fixture_activities(), &config);
+        assert_eq!(regions.len(), 2);
+        assert!(regions[0].count > regions[1].count);
+
+        let edinburgh = Point::new(-3.1883, 55.9533);
+        assert!(regions[1].centroid.haversine_distance(&edinburgh) < 10_000.0);
+        assert!(regions[1].bbox.intersects(&edinburgh));
+    }
+
+    /// What the dbscan crate makes of the same points, checking every pair
+    fn cluster_pairwise(points: &[Point], config: &ClusterConfig) -> Vec<Option<usize>> {
+        fn haversine(a: &[f64], b: &[f64]) -> f64 {
+            Point::new(a[0], a[1]).haversine_distance(&Point::new(b[0], b[1]))
+        }
+        let coords: Vec<Vec<f64>> = points.iter().map(|p| vec![p.x(), p.y()]).collect();
+        dbscan::Model::new(config.eps, config.min_points)
+            .set_distance_fn::<fn(&[f64], &[f64]) -> f64>(haversine)
+            .run(&coords)
+            .into_iter()
+            .map(|c| match c {
+                dbscan::Classification::Core(n) | dbscan::Classification::Edge(n) => Some(n),
+                dbscan::Classification::Noise => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_cluster_matches_pairwise() {
+        let centroids: Vec<Point> = fixture_activities()
+            .iter()
+            .filter_map(|a| a.linestring.as_ref()?.centroid())
+            .collect();
+        for (eps, min_points) in [(10_000.0, 10), (10_000.0, 5), (2_000.0, 3), (500.0, 2)] {
+            let config = ClusterConfig { eps, min_points };
+            let got = cluster(&centroids, &config);
+            assert_eq!(got, cluster_pairwise(&centroids, &config));
+            assert!(got.iter().any(|c| c.is_some()));
+        }
+    }
+
+    #[test]
+    fn test_useful_centroid_fallback() {
+        let config = ClusterConfig {
+            eps: 10_000.0,
+            min_points: 10,
+        };
+        // Too few to cluster, so we get the latest activity
+        let activities: Vec<Activity> = fixture_activities().into_iter().take(3).collect();
+        let latest = activities.iter().max_by_key(|a| a.start_date).unwrap();
+        let want = latest.linestring.as_ref().unwrap().centroid().unwrap();
+        let region = get_useful_centroid(&activities, &config).unwrap();
+        assert_eq!(region.centroid, want);
+        assert_eq!(region.count, 1);
+
+        assert!(get_useful_centroid(&[], &config).is_none());
+    }
+
+    #[test]
+    fn test_geojson_attribution() {
+        let activities = fixture_activities();
+        let attributions = crate::h3::attribute(&activities, &[]);
+        let GeoJson::FeatureCollection(fc) = to_geojson(activities, &attributions) else {
+            panic!("expected a feature collection");
+        };
+        for feature in fc.features {
+            let id = feature.property("id").and_then(|id| id.as_i64()).unwrap();
+            let want = attributions.iter().find(|a| a.id == id).unwrap();
+            let new_cells = feature.property("new_cells").and_then(|n| n.as_u64());
+            let total_cells = feature.property("total_cells").and_then(|n| n.as_u64());
+            assert_eq!(new_cells, Some(want.new_cells as u64));
+            assert_eq!(total_cells, Some(want.total_cells as u64));
+        }
+    }
+
+    #[test]
+    fn test_simplify_keeps_activities() {
+        let activities = simplify_all(fixture_activities(), zoom_to_tolerance(6.0));
+        assert_eq!(activities.len(), fixture_activities().len());
+        for a in activities {
+            if let Some(ls) = a.linestring {
+                assert!(ls.0.len() >= 2);
+            }
+        }
+    }
 }
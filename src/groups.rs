@@ -0,0 +1,387 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use geo::{LineString, Polygon};
+use geojson::{Feature, FeatureCollection, GeoJson, Geometry, JsonObject};
+use h3o::CellIndex;
+use rand::distributions::{Alphanumeric, DistString};
+use rocket::FromFormField;
+use serde::Serialize;
+
+use crate::h3;
+use crate::models::CellDb;
+use crate::stats::Interval;
+
+/// Length of the codes used to invite people to a group
+const INVITE_CODE_LEN: usize = 10;
+
+/// A fresh random code that lets people join a group
+pub fn invite_code() -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), INVITE_CODE_LEN)
+}
+
+/// How one member is doing in a group challenge
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Standing {
+    pub rank: usize,
+    pub user_id: i32,
+    pub cells: usize,
+    pub largest_cluster: usize,
+    pub new_this_month: usize,
+}
+
+impl Standing {
+    /// Work out a member's scores from their stored cells
+    /// Rank is filled in by leaderboard
+    pub fn new(user_id: i32, stored: &[CellDb], now: DateTime<Utc>) -> Self {
+        let cells: Vec<CellIndex> = stored.iter().filter_map(|c| c.cell_index()).collect();
+        let month = Interval::Month.start(now);
+        let new_this_month = stored
+            .iter()
+            .filter_map(|c| DateTime::from_timestamp(c.first_visited, 0))
+            .filter(|first_visited| Interval::Month.start(*first_visited) == month)
+            .count();
+        Standing {
+            rank: 0,
+            user_id,
+            cells: cells.len(),
+            largest_cluster: h3::largest_cluster(&cells),
+            new_this_month,
+        }
+    }
+}
+
+/// Rank members by visited cells, then largest cluster, then new cells this month
+/// Members with the same scores share a rank
+pub fn leaderboard(mut standings: Vec<Standing>) -> Vec<Standing> {
+    let key = |s: &Standing| (s.cells, s.largest_cluster, s.new_this_month);
+    standings.sort_by_key(|s| std::cmp::Reverse(key(s)));
+    for i in 0..standings.len() {
+        standings[i].rank = if i > 0 && key(&standings[i]) == key(&standings[i - 1]) {
+            standings[i - 1].rank
+        } else {
+            i + 1
+        };
+    }
+    standings
+}
+
+/// Colours to tell members apart on the territory map, in joining order
+const COLORS: [&str; 10] = [
+    "#e6194b", "#3cb44b", "#4363d8", "#f58231", "#911eb4", "#42d4f4", "#f032e6", "#bfef45",
+    "#469990", "#9a6324",
+];
+
+/// How to decide who owns a cell that several members have visited
+#[derive(Debug, Clone, Copy, PartialEq, FromFormField)]
+pub enum Ownership {
+    /// Whoever got there first
+    #[field(value = "first")]
+    First,
+    /// Whoever went there most, with ties going to whoever got there first
+    #[field(value = "most")]
+    Most,
+}
+
+/// A cell and the member who owns it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Claim {
+    pub cell: CellIndex,
+    pub owner: i32,
+    pub first_visited: i64,
+    pub visits: i32,
+}
+
+/// Decide who owns each cell visited by any member, sorted by cell
+pub fn territory(members: &[(i32, Vec<CellDb>)], ownership: Ownership) -> Vec<Claim> {
+    let mut claims: HashMap<CellIndex, Claim> = HashMap::new();
+    for (user_id, cells) in members {
+        for stored in cells {
+            let Some(cell) = stored.cell_index() else {
+                continue;
+            };
+            let claim = Claim {
+                cell,
+                owner: *user_id,
+                first_visited: stored.first_visited,
+                visits: stored.visits,
+            };
+            let first = (claim.first_visited, claim.owner);
+            claims
+                .entry(cell)
+                .and_modify(|current| {
+                    let current_first = (current.first_visited, current.owner);
+                    let better = match ownership {
+                        Ownership::First => first < current_first,
+                        Ownership::Most => {
+                            (std::cmp::Reverse(claim.visits), first)
+                                < (std::cmp::Reverse(current.visits), current_first)
+                        }
+                    };
+                    if better {
+                        *current = claim.clone();
+                    }
+                })
+                .or_insert(claim);
+        }
+    }
+    let mut claims: Vec<Claim> = claims.into_values().collect();
+    claims.sort_by_key(|c| c.cell);
+    claims
+}
+
+/// A cell polygon with its hex index in the `cell` property, plus the given ones
+fn cell_feature(cell: CellIndex, mut properties: JsonObject) -> Feature {
+    properties.insert("cell".to_string(), format!("{:x}", cell).into());
+    let polygon = Polygon::new(LineString::from(cell.boundary()), vec![]);
+    Feature {
+        geometry: Some(Geometry::from(&polygon)),
+        properties: Some(properties),
+        bbox: None,
+        id: None,
+        foreign_members: None,
+    }
+}
+
+fn collection(features: Vec<Feature>) -> GeoJson {
+    GeoJson::FeatureCollection(FeatureCollection {
+        bbox: None,
+        features,
+        foreign_members: None,
+    })
+}
+
+/// Cell polygons with `owner`, `color`, `first_visited` and `visits` properties
+/// Colours follow the order of `members`
+pub fn territory_geojson(claims: &[Claim], members: &[i32]) -> GeoJson {
+    let features = claims
+        .iter()
+        .map(|claim| {
+            let index = members.iter().position(|m| *m == claim.owner).unwrap_or(0);
+            let mut properties = JsonObject::new();
+            properties.insert("owner".to_string(), claim.owner.into());
+            properties.insert("color".to_string(), COLORS[index % COLORS.len()].into());
+            properties.insert("first_visited".to_string(), claim.first_visited.into());
+            properties.insert("visits".to_string(), claim.visits.into());
+            cell_feature(claim.cell, properties)
+        })
+        .collect();
+    collection(features)
+}
+
+/// How much two members' coverage has in common
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Overlap {
+    pub a: i32,
+    pub b: i32,
+    pub shared: usize,
+    /// Percent of a's cells that b has also visited
+    pub a_pct: f64,
+    /// Percent of b's cells that a has also visited
+    pub b_pct: f64,
+    /// Shared cells as a percent of the cells either has visited
+    pub union_pct: f64,
+}
+
+/// Everything the group has visited between them
+#[derive(Debug, Serialize)]
+pub struct Coverage {
+    /// Cells visited by anyone in the group
+    pub cells: usize,
+    /// Cells visited by more than one member
+    pub shared: usize,
+    /// Cells visited by each member
+    pub members: HashMap<i32, usize>,
+    pub overlaps: Vec<Overlap>,
+    /// The union of cells, with how many `members` visited each
+    pub geojson: GeoJson,
+}
+
+fn pct(part: usize, whole: usize) -> f64 {
+    match whole {
+        0 => 0.0,
+        _ => 100.0 * part as f64 / whole as f64,
+    }
+}
+
+impl Coverage {
+    /// Takes each member's visited cells, sorted
+    pub fn new(members: &[(i32, Vec<CellIndex>)]) -> Self {
+        let counts = h3::counts(members.iter().map(|(_, cells)| cells));
+        let shared = counts.values().filter(|n| **n > 1).count();
+
+        let mut overlaps: Vec<Overlap> = Vec::new();
+        for (i, (a, a_cells)) in members.iter().enumerate() {
+            for (b, b_cells) in &members[i + 1..] {
+                let both = h3::intersection(a_cells, b_cells).len();
+                let either = h3::union([a_cells, b_cells]).len();
+                overlaps.push(Overlap {
+                    a: *a,
+                    b: *b,
+                    shared: both,
+                    a_pct: pct(both, a_cells.len()),
+                    b_pct: pct(both, b_cells.len()),
+                    union_pct: pct(both, either),
+                });
+            }
+        }
+
+        let features = counts
+            .iter()
+            .map(|(cell, count)| {
+                let mut properties = JsonObject::new();
+                properties.insert("members".to_string(), (*count).into());
+                cell_feature(*cell, properties)
+            })
+            .collect();
+
+        Coverage {
+            cells: counts.len(),
+            shared,
+            members: members.iter().map(|(m, cells)| (*m, cells.len())).collect(),
+            overlaps,
+            geojson: collection(features),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::geo::fixture_activities;
+
+    fn standing(user_id: i32, cells: usize, largest_cluster: usize) -> Standing {
+        Standing {
+            rank: 0,
+            user_id,
+            cells,
+            largest_cluster,
+            new_this_month: 0,
+        }
+    }
+
+    #[test]
+    fn test_invite_code() {
+        let code = invite_code();
+        assert_eq!(code.len(), INVITE_CODE_LEN);
+        assert!(code.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_ne!(code, invite_code());
+    }
+
+    #[test]
+    fn test_leaderboard() {
+        let got = leaderboard(vec![
+            standing(1, 10, 5),
+            standing(2, 30, 1),
+            standing(3, 10, 7),
+            standing(4, 10, 5),
+        ]);
+        let order: Vec<(i32, usize)> = got.iter().map(|s| (s.user_id, s.rank)).collect();
+        assert_eq!(order, vec![(2, 1), (3, 2), (1, 3), (4, 3)]);
+    }
+
+    fn stored(user_id: i32, cell: CellIndex, first_visited: i64, visits: i32) -> CellDb {
+        CellDb {
+            user_id,
+            cell: u64::from(cell) as i64,
+            first_visited,
+            visits,
+        }
+    }
+
+    #[test]
+    fn test_territory() {
+        let a = h3o::LatLng::new(51.5072, -0.1276)
+            .unwrap()
+            .to_cell(h3::RESOLUTION);
+        let b = h3o::LatLng::new(55.9533, -3.1883)
+            .unwrap()
+            .to_cell(h3::RESOLUTION);
+        let members = vec![
+            (1, vec![stored(1, a, 100, 1), stored(1, b, 300, 2)]),
+            (2, vec![stored(2, a, 200, 5), stored(2, b, 200, 2)]),
+        ];
+
+        let owners = |claims: Vec<Claim>| -> Vec<(CellIndex, i32)> {
+            claims.iter().map(|c| (c.cell, c.owner)).collect()
+        };
+        let mut want = vec![(a, 1), (b, 2)];
+        want.sort();
+        assert_eq!(owners(territory(&members, Ownership::First)), want);
+        // Most visits for a, and a tie on b goes to whoever was first
+        let mut want = vec![(a, 2), (b, 2)];
+        want.sort();
+        assert_eq!(owners(territory(&members, Ownership::Most)), want);
+
+        let claims = territory(&members, Ownership::First);
+        let GeoJson::FeatureCollection(fc) = territory_geojson(&claims, &[1, 2]) else {
+            panic!("expected a feature collection");
+        };
+        assert_eq!(fc.features.len(), 2);
+        let colors: Vec<&str> = fc
+            .features
+            .iter()
+            .filter_map(|f| f.property("color")?.as_str())
+            .collect();
+        assert_ne!(colors[0], colors[1]);
+    }
+
+    #[test]
+    fn test_coverage() {
+        let center = h3o::LatLng::new(51.5072, -0.1276)
+            .unwrap()
+            .to_cell(h3::RESOLUTION);
+        let mut a: Vec<CellIndex> = center.grid_disk(1);
+        a.sort();
+        let b = vec![center];
+        let c = vec![h3o::LatLng::new(55.9533, -3.1883)
+            .unwrap()
+            .to_cell(h3::RESOLUTION)];
+
+        let coverage = Coverage::new(&[(1, a), (2, b), (3, c)]);
+        assert_eq!(coverage.cells, 8);
+        assert_eq!(coverage.shared, 1);
+        assert_eq!(coverage.members[&1], 7);
+        assert_eq!(coverage.overlaps.len(), 3);
+
+        let ab = &coverage.overlaps[0];
+        assert_eq!((ab.a, ab.b, ab.shared), (1, 2, 1));
+        assert!((ab.a_pct - 100.0 / 7.0).abs() < 1e-9);
+        assert_eq!(ab.b_pct, 100.0);
+        let bc = &coverage.overlaps[2];
+        assert_eq!((bc.a, bc.b, bc.shared, bc.union_pct), (2, 3, 0, 0.0));
+
+        let GeoJson::FeatureCollection(fc) = &coverage.geojson else {
+            panic!("expected a feature collection");
+        };
+        assert_eq!(fc.features.len(), coverage.cells);
+    }
+
+    #[test]
+    fn test_standing() {
+        let activities = fixture_activities();
+        let stored: Vec<CellDb> = h3::visits(&activities)
+            .iter()
+            .map(|v| CellDb::from_visit(1, v))
+            .collect();
+        let last = activities.iter().map(|a| a.start_date).max().unwrap();
+        let got = Standing::new(1, &stored, last);
+        assert_eq!(got.cells, h3::polyfill_all(&activities).len());
+        assert!(got.largest_cluster > 0 && got.largest_cluster <= got.cells);
+
+        // Same as the new cells from the activities this month
+        let month = Interval::Month.start(last);
+        let want: usize = h3::attribute(&activities, &[])
+            .iter()
+            .filter(|a| Interval::Month.start(a.start_date) == month)
+            .map(|a| a.new_cells)
+            .sum();
+        assert!(want > 0);
+        assert_eq!(got.new_this_month, want);
+
+        let later = Utc.with_ymd_and_hms(2030, 1, 1, 0, 0, 0).unwrap();
+        assert_eq!(Standing::new(1, &stored, later).new_this_month, 0);
+    }
+}
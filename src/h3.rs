@@ -1,22 +1,38 @@
-use geo;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use chrono::{DateTime, Utc};
+use geo::{self, BoundingRect, HaversineDistance};
 use h3o::{
-    geom::{LineString, PolyfillConfig, ToCells},
-    CellIndex, Resolution,
+    geom::{ContainmentMode, LineString, PolyfillConfig, Rect, ToCells},
+    CellIndex, LatLng, Resolution,
 };
 
+use crate::error;
 use crate::models::Activity;
+use crate::privacy::{self, Zone};
+use crate::sport::Category;
+
+/// The resolution all visited cells are computed at
+pub const RESOLUTION: Resolution = Resolution::Nine;
+
+/// Roughly how many coarse cells to cover an area with in `ranges_within`
+const MAX_COVER: usize = 64;
 
 fn polyfill(linestring: &geo::LineString) -> Vec<CellIndex> {
     let coords: Vec<geo::Coord> = linestring.to_owned().into_inner();
     let linestring = geo::LineString::new(coords);
     let linestring = LineString::from_degrees(linestring).unwrap();
     let cells = linestring
-        .to_cells(PolyfillConfig::new(Resolution::Nine))
+        .to_cells(PolyfillConfig::new(RESOLUTION))
         .collect::<Vec<_>>();
     cells
 }
 
-pub fn polyfill_all(activities: &Vec<Activity>) -> Vec<CellIndex> {
+pub fn polyfill_all(activities: &[Activity]) -> Vec<CellIndex> {
+    polyfill_iter(activities.iter())
+}
+
+fn polyfill_iter<'a>(activities: impl Iterator<Item = &'a Activity>) -> Vec<CellIndex> {
     let mut cells: Vec<CellIndex> = Vec::new();
     for activity in activities {
         let new_cells = match &activity.linestring {
@@ -29,3 +45,422 @@ pub fn polyfill_all(activities: &Vec<Activity>) -> Vec<CellIndex> {
     cells.dedup();
     cells
 }
+
+/// Visited cells for each sport category
+pub fn polyfill_by_category(activities: &[Activity]) -> BTreeMap<Category, Vec<CellIndex>> {
+    let categories: BTreeSet<Category> =
+        activities.iter().map(|a| a.sport_type.category()).collect();
+    categories
+        .into_iter()
+        .map(|category| {
+            let matching = activities
+                .iter()
+                .filter(|a| a.sport_type.category() == category);
+            (category, polyfill_iter(matching))
+        })
+        .collect()
+}
+
+/// How many cells an activity went through, and how many of those
+/// hadn't been visited by any earlier activity
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attribution {
+    pub id: i64,
+    pub start_date: DateTime<Utc>,
+    pub total_cells: usize,
+    pub new_cells: usize,
+    /// The cells this activity was first to visit, sorted
+    pub first_visited: Vec<CellIndex>,
+}
+
+/// Each activity (oldest first) with the distinct cells it went through
+/// leaving out any in privacy zones that shouldn't count
+fn chronological<'a>(
+    activities: &'a [Activity],
+    zones: &'a [Zone],
+) -> impl Iterator<Item = (&'a Activity, Vec<CellIndex>)> {
+    let mut ordered: Vec<&Activity> = activities.iter().collect();
+    ordered.sort_by_key(|a| (a.start_date, a.id));
+    ordered.into_iter().map(|activity| {
+        let mut cells = match &activity.linestring {
+            Some(ls) => polyfill(ls),
+            None => vec![],
+        };
+        cells.sort();
+        cells.dedup();
+        (activity, privacy::mask_cells(cells, zones))
+    })
+}
+
+/// Work out which activity first visited each cell, going through them in
+/// chronological order. Returns one Attribution per activity, oldest first
+/// Cells in privacy zones that shouldn't count aren't credited to anything
+pub fn attribute(activities: &[Activity], zones: &[Zone]) -> Vec<Attribution> {
+    let mut seen: HashSet<CellIndex> = HashSet::new();
+    chronological(activities, zones)
+        .map(|(activity, cells)| {
+            let total_cells = cells.len();
+            let first_visited: Vec<CellIndex> =
+                cells.into_iter().filter(|c| seen.insert(*c)).collect();
+            Attribution {
+                id: activity.id,
+                start_date: activity.start_date,
+                total_cells,
+                new_cells: first_visited.len(),
+                first_visited,
+            }
+        })
+        .collect()
+}
+
+/// When a cell was first visited, and by how many activities
+#[derive(Debug, Clone, PartialEq)]
+pub struct Visit {
+    pub cell: CellIndex,
+    pub first_visited: DateTime<Utc>,
+    pub visits: usize,
+}
+
+/// Every visited cell with its first visit and visit count, sorted by cell
+pub fn visits(activities: &[Activity]) -> Vec<Visit> {
+    let mut visits: BTreeMap<CellIndex, Visit> = BTreeMap::new();
+    for (activity, cells) in chronological(activities, &[]) {
+        for cell in cells {
+            visits
+                .entry(cell)
+                .or_insert(Visit {
+                    cell,
+                    first_visited: activity.start_date,
+                    visits: 0,
+                })
+                .visits += 1;
+        }
+    }
+    visits.into_values().collect()
+}
+
+/// An unvisited cell with visited cells around it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gap {
+    pub cell: CellIndex,
+    /// How many of its (usually six) neighbours have been visited
+    pub visited_neighbours: usize,
+    /// Metres from home to the centre of the cell
+    pub distance: f64,
+}
+
+/// Centre of a cell as a geo Point
+pub fn center(cell: CellIndex) -> geo::Point {
+    let ll = LatLng::from(cell);
+    geo::Point::new(ll.lng(), ll.lat())
+}
+
+/// Find holes in the coverage: unvisited cells with at least `min_neighbours`
+/// visited neighbours, nearest to `home` first
+pub fn find_gaps(visited: &[CellIndex], min_neighbours: usize, home: geo::Point) -> Vec<Gap> {
+    let visited_set: HashSet<CellIndex> = visited.iter().copied().collect();
+    let mut counts: HashMap<CellIndex, usize> = HashMap::new();
+    for cell in visited {
+        for neighbour in cell.grid_disk::<Vec<_>>(1) {
+            if !visited_set.contains(&neighbour) {
+                *counts.entry(neighbour).or_default() += 1;
+            }
+        }
+    }
+    let mut gaps: Vec<Gap> = counts
+        .into_iter()
+        .filter(|(_, count)| *count >= min_neighbours)
+        .map(|(cell, visited_neighbours)| Gap {
+            cell,
+            visited_neighbours,
+            distance: center(cell).haversine_distance(&home),
+        })
+        .collect();
+    gaps.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+    gaps
+}
+
+/// Size of the biggest group of visited cells that are all joined up
+/// through their neighbours
+pub fn largest_cluster(visited: &[CellIndex]) -> usize {
+    let mut remaining: HashSet<CellIndex> = visited.iter().copied().collect();
+    let mut largest = 0;
+    while let Some(&seed) = remaining.iter().next() {
+        remaining.remove(&seed);
+        let mut stack = vec![seed];
+        let mut size = 0;
+        while let Some(cell) = stack.pop() {
+            size += 1;
+            for neighbour in cell.grid_disk::<Vec<_>>(1) {
+                if remaining.remove(&neighbour) {
+                    stack.push(neighbour);
+                }
+            }
+        }
+        largest = largest.max(size);
+    }
+    largest
+}
+
+/// All cells in any of the given sets, sorted and deduped
+pub fn union<'a>(sets: impl IntoIterator<Item = &'a Vec<CellIndex>>) -> Vec<CellIndex> {
+    let mut cells: Vec<CellIndex> = sets.into_iter().flatten().copied().collect();
+    cells.sort();
+    cells.dedup();
+    cells
+}
+
+/// Cells in both of the given sets, which must be sorted
+pub fn intersection(a: &[CellIndex], b: &[CellIndex]) -> Vec<CellIndex> {
+    let mut out: Vec<CellIndex> = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                out.push(a[i]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    out
+}
+
+/// How many of the given sets each cell is in
+pub fn counts<'a>(
+    sets: impl IntoIterator<Item = &'a Vec<CellIndex>>,
+) -> BTreeMap<CellIndex, usize> {
+    let mut counts: BTreeMap<CellIndex, usize> = BTreeMap::new();
+    for cell in sets.into_iter().flatten() {
+        *counts.entry(*cell).or_default() += 1;
+    }
+    counts
+}
+
+/// Bounding box around the centres of the given cells
+pub fn bbox(cells: &[CellIndex]) -> Option<geo::Rect> {
+    let centers: geo::MultiPoint = cells.iter().map(|c| center(*c)).collect();
+    centers.bounding_rect()
+}
+
+/// Compact a set of cells into mixed resolutions
+/// so that wherever all the children of a parent are present, only the parent is kept
+/// Expects the cells from polyfill_all (same resolution, sorted and deduped)
+pub fn compact(cells: Vec<CellIndex>) -> Result<Vec<CellIndex>, error::Error> {
+    let mut compacted: Vec<CellIndex> = CellIndex::compact(cells)?.collect();
+    compacted.sort();
+    Ok(compacted)
+}
+
+/// Ranges (first and last, inclusive) of cell indexes at RESOLUTION that between them
+/// hold every cell whose centre is in `bounds`, so stored cells can be looked up by range
+/// Each range is the children of a coarser cell, as those are numbered consecutively
+/// None if the area is too wide to be worth it
+pub fn ranges_within(bounds: geo::Rect) -> Option<Vec<(CellIndex, CellIndex)>> {
+    if bounds.width() >= 180.0 {
+        return None;
+    }
+    let rect = Rect::from_degrees(bounds).ok()?;
+    // The finest resolution that still covers it with a handful of cells
+    let mut cover: Vec<CellIndex> = Vec::new();
+    for res in Resolution::range(Resolution::Zero, RESOLUTION) {
+        let config = PolyfillConfig::new(res).containment_mode(ContainmentMode::Covers);
+        let cells: Vec<CellIndex> = rect.to_cells(config).collect();
+        if cells.len() > MAX_COVER && !cover.is_empty() {
+            break;
+        }
+        cover = cells;
+    }
+    // Children can stick out of their parent a little, but never past its neighbours
+    let mut parents: Vec<CellIndex> = cover
+        .iter()
+        .flat_map(|c| c.grid_disk::<Vec<_>>(1))
+        .collect();
+    parents.sort();
+    parents.dedup();
+    let ranges = parents
+        .into_iter()
+        .filter_map(|p| {
+            let last = p.children_count(RESOLUTION) - 1;
+            Some((p.child_at(0, RESOLUTION)?, p.child_at(last, RESOLUTION)?))
+        })
+        .collect();
+    Some(ranges)
+}
+
+/// The reverse of compact, giving back all cells at RESOLUTION
+pub fn uncompact(cells: &[CellIndex]) -> Vec<CellIndex> {
+    let mut uncompacted: Vec<CellIndex> =
+        CellIndex::uncompact(cells.iter().copied(), RESOLUTION).collect();
+    uncompacted.sort();
+    uncompacted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geo::fixture_activities;
+
+    #[test]
+    fn test_compact_filled_parent() {
+        // A completely filled res 7 cell, plus one neighbour
+        let parent = LatLng::new(51.5072, -0.1276)
+            .unwrap()
+            .to_cell(Resolution::Seven);
+        let mut cells: Vec<CellIndex> = parent.children(RESOLUTION).collect();
+        let outside = LatLng::new(51.6, -0.2).unwrap().to_cell(RESOLUTION);
+        cells.push(outside);
+        cells.sort();
+
+        let compacted = compact(cells.clone()).unwrap();
+        assert_eq!(compacted.len(), 2);
+        assert!(compacted.contains(&parent));
+        assert_eq!(uncompact(&compacted), cells);
+    }
+
+    #[test]
+    fn test_polyfill_by_category() {
+        let activities = fixture_activities();
+        let by_category = polyfill_by_category(&activities);
+        assert!(by_category.contains_key(&Category::Ride));
+        assert!(by_category.contains_key(&Category::Water));
+        // Swims have no polyline, so no cells
+        assert_eq!(by_category.get(&Category::Swim), Some(&vec![]));
+        assert_eq!(union(by_category.values()), polyfill_all(&activities));
+    }
+
+    #[test]
+    fn test_attribute() {
+        let activities = fixture_activities();
+        let attributions = attribute(&activities, &[]);
+        assert_eq!(attributions.len(), activities.len());
+        assert!(attributions
+            .windows(2)
+            .all(|w| w[0].start_date <= w[1].start_date));
+        assert!(attributions.iter().all(|a| a.new_cells <= a.total_cells));
+        // The first activity with a line discovers everything it visits
+        let first = attributions.iter().find(|a| a.total_cells > 0).unwrap();
+        assert_eq!(first.new_cells, first.total_cells);
+        let new: usize = attributions.iter().map(|a| a.new_cells).sum();
+        assert_eq!(new, polyfill_all(&activities).len());
+    }
+
+    #[test]
+    fn test_visits() {
+        let activities = fixture_activities();
+        let visits = visits(&activities);
+        let cells: Vec<CellIndex> = visits.iter().map(|v| v.cell).collect();
+        assert_eq!(cells, polyfill_all(&activities));
+        // London gets visited over and over
+        assert!(visits.iter().any(|v| v.visits > 1));
+
+        let first = attribute(&activities, &[]);
+        for visit in visits {
+            let discoverer = first
+                .iter()
+                .find(|a| a.first_visited.contains(&visit.cell))
+                .unwrap();
+            assert_eq!(visit.first_visited, discoverer.start_date);
+        }
+    }
+
+    #[test]
+    fn test_find_gaps() {
+        // A ring of cells around a hole, plus one cell further away
+        let hole = LatLng::new(51.5072, -0.1276).unwrap().to_cell(RESOLUTION);
+        let mut visited: Vec<CellIndex> = hole.grid_ring_fast(1).flatten().collect();
+        let far = LatLng::new(51.6, -0.2).unwrap().to_cell(RESOLUTION);
+        visited.push(far);
+
+        let home = center(hole);
+        let gaps = find_gaps(&visited, 6, home);
+        assert_eq!(gaps.len(), 1);
+        assert_eq!(gaps[0].cell, hole);
+        assert_eq!(gaps[0].visited_neighbours, 6);
+        assert!(gaps[0].distance < 1.0);
+
+        // With a lower bar, cells just outside the ring count too
+        let gaps = find_gaps(&visited, 2, home);
+        assert!(gaps.len() > 1);
+        assert_eq!(gaps[0].cell, hole);
+        assert!(gaps.windows(2).all(|w| w[0].distance <= w[1].distance));
+        assert!(gaps.iter().all(|g| !visited.contains(&g.cell)));
+    }
+
+    #[test]
+    fn test_largest_cluster() {
+        let london = LatLng::new(51.5072, -0.1276).unwrap().to_cell(RESOLUTION);
+        let mut visited: Vec<CellIndex> = london.grid_disk(1);
+        let edinburgh = LatLng::new(55.9533, -3.1883).unwrap().to_cell(RESOLUTION);
+        visited.extend(edinburgh.grid_disk::<Vec<_>>(2));
+        assert_eq!(largest_cluster(&visited), 19);
+        assert_eq!(largest_cluster(&[london]), 1);
+        assert_eq!(largest_cluster(&[]), 0);
+    }
+
+    #[test]
+    fn test_set_operations() {
+        let london = LatLng::new(51.5072, -0.1276).unwrap().to_cell(RESOLUTION);
+        let mut a: Vec<CellIndex> = london.grid_disk(1);
+        let mut b: Vec<CellIndex> =
+            vec![london, LatLng::new(51.6, -0.2).unwrap().to_cell(RESOLUTION)];
+        a.sort();
+        b.sort();
+        assert_eq!(intersection(&a, &b), vec![london]);
+        assert_eq!(intersection(&a, &[]), vec![]);
+
+        let counts = counts([&a, &b]);
+        assert_eq!(counts.len(), union([&a, &b]).len());
+        assert_eq!(counts[&london], 2);
+        assert_eq!(counts.values().filter(|n| **n > 1).count(), 1);
+
+        let rect = bbox(&a).unwrap();
+        assert!(a
+            .iter()
+            .all(|c| geo::Intersects::intersects(&rect, &center(*c))));
+        assert!(bbox(&[]).is_none());
+    }
+
+    #[test]
+    fn test_compact_round_trip() {
+        let cells = polyfill_all(&fixture_activities());
+        let compacted = compact(cells.clone()).unwrap();
+        assert!(compacted.len() <= cells.len());
+        assert_eq!(uncompact(&compacted), cells);
+    }
+
+    #[test]
+    fn test_ranges_within() {
+        let bounds = geo::Rect::new(
+            geo::Coord { x: -0.2, y: 51.45 },
+            geo::Coord { x: -0.05, y: 51.55 },
+        );
+        let ranges = ranges_within(bounds).unwrap();
+        assert!(ranges.len() <= MAX_COVER * 7);
+        let within = |cell: CellIndex| ranges.iter().any(|(a, b)| (*a..=*b).contains(&cell));
+
+        // Every cell with its centre in the bounds, including right at the edges
+        let steps = 50;
+        for i in 0..=steps {
+            for j in 0..=steps {
+                let lng = bounds.min().x + bounds.width() * i as f64 / steps as f64;
+                let lat = bounds.min().y + bounds.height() * j as f64 / steps as f64;
+                let cell = LatLng::new(lat, lng).unwrap().to_cell(RESOLUTION);
+                assert!(within(cell), "{} missing", cell);
+            }
+        }
+        let edinburgh = LatLng::new(55.9533, -3.1883).unwrap().to_cell(RESOLUTION);
+        assert!(!within(edinburgh));
+
+        let world = geo::Rect::new(
+            geo::Coord {
+                x: -180.0,
+                y: -85.0,
+            },
+            geo::Coord { x: 180.0, y: 85.0 },
+        );
+        assert!(ranges_within(world).is_none());
+    }
+}
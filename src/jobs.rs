@@ -0,0 +1,171 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use chrono::Utc;
+use log::{info, warn};
+use rocket::fairing::AdHoc;
+
+use h3o::CellIndex;
+
+use crate::db::{self, Db};
+use crate::error;
+use crate::events::{Event, Events};
+use crate::models::JobDb;
+use crate::privacy::{self, Zone};
+use crate::strava::{self, PER_PAGE};
+use crate::{geo, h3, sync};
+
+/// Give up on a job after this many tries
+const MAX_ATTEMPTS: i32 = 3;
+
+/// How long the worker waits before looking at the queue again
+/// when it's empty or the last job didn't work
+const POLL: Duration = Duration::from_secs(2);
+
+/// Fetch the user's whole history a page at a time, within the shared budget, saving each page
+/// as it comes so that the progress shows up in `/sync/status`,
+/// and sending the progress and any new cells to `/events`
+/// Returns how many activities were fetched, or None if the job was
+/// deleted along with its user part way through
+async fn run(
+    conn: &Db,
+    events: &Events,
+    client: &strava::StravaClient,
+    budget: &sync::Budget,
+    job: &JobDb,
+) -> Result<Option<usize>, error::Error> {
+    let user_id = job.user_id;
+    let token = sync::get_token(conn, user_id).await?;
+    let zones: Vec<Zone> = db::get_privacy_zones(conn, user_id)
+        .await?
+        .iter()
+        .map(Zone::from)
+        .collect();
+    let mut known: HashSet<CellIndex> = db::get_cells(conn, user_id)
+        .await?
+        .iter()
+        .filter_map(|c| c.cell_index())
+        .collect();
+    // Only needed for the progress bar, so carry on without it
+    let total = if budget.try_spend(1) {
+        client
+            .get_athlete_stats(&token, job.user_id)
+            .await
+            .ok()
+            .map(|stats| stats.count() as i32)
+    } else {
+        None
+    };
+    let mut fetched = 0;
+    for page in 1.. {
+        budget.spend().await;
+        let activities = client.get_activities_page(&token, page).await?;
+        if activities.is_empty() {
+            break;
+        }
+        // Otherwise the deleted user's activities would be saved all over again
+        if !db::job_exists(conn, job.id).await? {
+            return Ok(None);
+        }
+        db::save_activities(conn, user_id, &activities).await?;
+        let count = activities.len();
+        fetched += count;
+        let now = Utc::now().timestamp();
+        db::update_job_progress(conn, job.id, fetched as i32, total, now).await?;
+        events.send(
+            user_id,
+            Event::Progress {
+                fetched,
+                total: total.map(|t| t as usize),
+            },
+        );
+
+        let cells = privacy::mask_cells(h3::polyfill_all(&geo::decode_all(activities)), &zones);
+        let cells: Vec<String> = cells
+            .into_iter()
+            .filter(|c| known.insert(*c))
+            .map(|c| format!("{:x}", c))
+            .collect();
+        if !cells.is_empty() {
+            events.send(user_id, Event::Cells { cells });
+        }
+
+        if count < PER_PAGE {
+            break;
+        }
+    }
+    db::update_cells(conn, user_id).await?;
+    Ok(Some(fetched))
+}
+
+/// Run the next job in the queue, if there is one
+/// Returns the status it ended up with
+pub async fn work_once(
+    conn: &Db,
+    events: &Events,
+    client: &strava::StravaClient,
+    budget: &sync::Budget,
+) -> Result<Option<&'static str>, error::Error> {
+    let Some(job) = db::claim_job(conn, Utc::now().timestamp()).await? else {
+        return Ok(None);
+    };
+    let (status, last_error) = match run(conn, events, client, budget, &job).await {
+        Ok(None) => {
+            info!("job {} was cancelled", job.id);
+            return Ok(Some("cancelled"));
+        }
+        Ok(Some(fetched)) => {
+            info!("job {} fetched {} activities", job.id, fetched);
+            events.send(job.user_id, Event::Done { fetched });
+            ("done", None)
+        }
+        Err(e) => {
+            let message = e.redacted();
+            events.send(
+                job.user_id,
+                Event::Failed {
+                    message: message.clone(),
+                },
+            );
+            warn!("job {} failed (attempt {}): {}", job.id, job.attempts, e.0);
+            let status = if job.attempts >= MAX_ATTEMPTS {
+                "failed"
+            } else {
+                "queued"
+            };
+            (status, Some(message))
+        }
+    };
+    db::finish_job(conn, job.id, status, last_error, Utc::now().timestamp()).await?;
+    Ok(Some(status))
+}
+
+/// Works through the job queue in a background task, one job at a time
+pub fn fairing() -> AdHoc {
+    AdHoc::on_liftoff("Job worker", |rocket| {
+        Box::pin(async move {
+            let Some(conn) = Db::get_one(rocket).await else {
+                warn!("no db connection for the job worker");
+                return;
+            };
+            let events = rocket.state::<Events>().cloned().unwrap_or_default();
+            let client = strava::StravaClient::default();
+            let budget = rocket.state::<sync::Budget>().cloned().unwrap_or_default();
+            rocket::tokio::spawn(async move {
+                match db::requeue_running_jobs(&conn).await {
+                    Ok(0) => (),
+                    Ok(count) => info!("requeued {} interrupted jobs", count),
+                    Err(e) => warn!("failed to requeue jobs: {}", e.0),
+                }
+                loop {
+                    match work_once(&conn, &events, &client, &budget).await {
+                        Ok(Some("done")) => continue,
+                        Ok(_) => (),
+                        Err(e) => warn!("job worker failed: {}", e.0),
+                    }
+                    rocket::tokio::time::sleep(POLL).await;
+                }
+            });
+        })
+    })
+}
@@ -2,12 +2,23 @@
 //!
 //! Playing around with the Strava API
 
+pub mod compact;
 pub mod crypto;
 pub mod db;
 pub mod error;
+pub mod events;
+pub mod export;
 pub mod geo;
+pub mod groups;
 pub mod h3;
+pub mod jobs;
 pub mod models;
+pub mod privacy;
+pub mod route;
 pub mod routes;
 pub mod schema;
+pub mod sport;
+pub mod stats;
 pub mod strava;
+pub mod sync;
+pub mod tiles;
@@ -1,17 +1,23 @@
-use chrono::{DateTime, NaiveDateTime, Utc};
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
 use diesel::prelude::*;
-use geo::{LineString, Point};
+use geo::{BoundingRect, Coord, Intersects, LineString, Point, Rect};
 use geojson::GeoJson;
 use geojson::{JsonObject, JsonValue};
+use h3o::CellIndex;
 use polyline;
+use rocket::form::{self, FromForm, FromFormField, ValueField};
 use rocket::http::Status;
 use rocket::request::Outcome;
 use rocket::request::{FromRequest, Request};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
+use crate::h3;
+use crate::sport::{Category, SportType};
 use crate::strava::ActivityResponse;
 
-#[derive(Debug, Queryable, Selectable, Insertable)]
+#[derive(Debug, Clone, Queryable, Selectable, Insertable)]
 #[diesel(table_name = crate::schema::users)]
 #[diesel(check_for_backend(diesel::sqlite::Sqlite))]
 pub struct UserDb {
@@ -41,11 +47,311 @@ impl<'r> FromRequest<'r> for User {
     }
 }
 
+/// A user's visited cell, as stored after each sync
+#[derive(Debug, Clone, Queryable, Selectable, Insertable)]
+#[diesel(table_name = crate::schema::cells)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct CellDb {
+    pub user_id: i32,
+    pub cell: i64,
+    pub first_visited: i64,
+    pub visits: i32,
+}
+
+impl CellDb {
+    pub fn from_visit(user_id: i32, visit: &h3::Visit) -> Self {
+        CellDb {
+            user_id,
+            cell: u64::from(visit.cell) as i64,
+            first_visited: visit.first_visited.timestamp(),
+            visits: visit.visits as i32,
+        }
+    }
+
+    pub fn cell_index(&self) -> Option<CellIndex> {
+        CellIndex::try_from(self.cell as u64).ok()
+    }
+}
+
+/// A hashed token for a user's public share link (at most one each)
+#[derive(Debug, Clone, Queryable, Selectable, Insertable)]
+#[diesel(table_name = crate::schema::share_tokens)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct ShareTokenDb {
+    pub user_id: i32,
+    pub token_hash: String,
+    pub created_at: i64,
+}
+
+/// A circle that activity lines are trimmed at, see privacy::Zone
+#[derive(Debug, Clone, Queryable, Selectable, Serialize)]
+#[diesel(table_name = crate::schema::privacy_zones)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct PrivacyZoneDb {
+    pub id: i32,
+    pub user_id: i32,
+    pub lat: f64,
+    pub lng: f64,
+    pub radius: f64,
+    pub count_cells: bool,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::schema::privacy_zones)]
+pub struct NewPrivacyZoneDb {
+    pub user_id: i32,
+    pub lat: f64,
+    pub lng: f64,
+    pub radius: f64,
+    pub count_cells: bool,
+}
+
+/// Body of `POST /privacy/zones`
+/// Set `count_cells=false` to also leave the zone's cells out of coverage
+#[derive(Debug, FromForm)]
+pub struct ZoneForm {
+    #[field(validate = with(|x| (-90.0..=90.0).contains(x), "invalid latitude"))]
+    pub lat: f64,
+    #[field(validate = with(|x| (-180.0..=180.0).contains(x), "invalid longitude"))]
+    pub lng: f64,
+    /// In metres
+    #[field(validate = with(|x| (1.0..=10_000.0).contains(x), "radius must be 1-10000m"))]
+    pub radius: f64,
+    /// Defaults to true
+    pub count_cells: Option<bool>,
+}
+
+/// A group of users competing over hexagons
+#[derive(Debug, Clone, Queryable, Selectable, Serialize)]
+#[diesel(table_name = crate::schema::groups)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct GroupDb {
+    pub id: i32,
+    pub name: String,
+    pub invite_code: String,
+    pub owner_id: i32,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::schema::groups)]
+pub struct NewGroupDb {
+    pub name: String,
+    pub invite_code: String,
+    pub owner_id: i32,
+    pub created_at: i64,
+}
+
+/// Body of `POST /groups`
+#[derive(Debug, FromForm)]
+pub struct GroupForm {
+    #[field(validate = len(1..=100))]
+    pub name: String,
+}
+
+/// Body of `POST /groups/join`
+#[derive(Debug, FromForm)]
+pub struct JoinForm {
+    pub code: String,
+}
+
+#[derive(Debug, Queryable, Selectable, Insertable)]
+#[diesel(table_name = crate::schema::group_members)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct GroupMemberDb {
+    pub group_id: i32,
+    pub user_id: i32,
+    pub joined_at: i64,
+}
+
+/// The outcome of a token refresh or sync run by the scheduler
+#[derive(Debug, Clone, Queryable, Selectable, Serialize)]
+#[diesel(table_name = crate::schema::sync_jobs)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct SyncJobDb {
+    pub id: i32,
+    pub user_id: i32,
+    pub kind: String,
+    pub status: String,
+    pub started_at: i64,
+    pub finished_at: i64,
+    pub activities: i32,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::schema::sync_jobs)]
+pub struct NewSyncJobDb {
+    pub user_id: i32,
+    pub kind: String,
+    pub status: String,
+    pub started_at: i64,
+    pub finished_at: i64,
+    pub activities: i32,
+    pub error: Option<String>,
+}
+
+/// A full-history sync in the job queue, see jobs::worker
+/// `status` goes queued -> running -> done, or back to queued to retry until failed
+#[derive(Debug, Clone, Queryable, Selectable, Serialize)]
+#[diesel(table_name = crate::schema::jobs)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct JobDb {
+    pub id: i32,
+    pub user_id: i32,
+    pub status: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    /// Activities fetched so far
+    pub fetched: i32,
+    /// Roughly how many there are to fetch, if Strava told us
+    pub total: Option<i32>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::schema::jobs)]
+pub struct NewJobDb {
+    pub user_id: i32,
+    pub status: String,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+/// An area with a bunch of activities in it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Region {
+    pub centroid: Point,
+    pub bbox: Rect,
+    pub count: usize,
+}
+
+/// An unvisited cell worth going to, from h3::find_gaps
+#[derive(Debug, Serialize)]
+pub struct Target {
+    pub cell: String,
+    pub center: Point,
+    pub visited_neighbours: usize,
+    pub distance: f64,
+}
+
 #[derive(Serialize)]
 pub struct Data {
     pub activities: Option<GeoJson>,
     pub cells: Vec<String>,
     pub centroid: Option<Point>,
+    pub bbox: Option<Rect>,
+    pub regions: Vec<Region>,
+    pub sport_cells: BTreeMap<Category, Vec<String>>,
+}
+
+/// A date query param like `2024-01-31`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DateParam(pub NaiveDate);
+
+#[rocket::async_trait]
+impl<'v> FromFormField<'v> for DateParam {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        NaiveDate::parse_from_str(field.value, "%Y-%m-%d")
+            .map(DateParam)
+            .map_err(|_| form::Error::validation("expected a date like 2024-01-31").into())
+    }
+}
+
+/// A bbox query param like `min_lng,min_lat,max_lng,max_lat`
+/// Not an `Option<BboxParam>`, as Rocket quietly turns a malformed one of those into `None`
+/// so it'd match everything rather than be rejected
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct BboxParam(pub Option<Rect>);
+
+#[rocket::async_trait]
+impl<'v> FromFormField<'v> for BboxParam {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        let invalid = || form::Error::validation("expected min_lng,min_lat,max_lng,max_lat");
+        let nums: Vec<f64> = field
+            .value
+            .split(',')
+            .map(|n| n.trim().parse().ok().filter(|n: &f64| n.is_finite()))
+            .collect::<Option<_>>()
+            .ok_or_else(invalid)?;
+        match nums[..] {
+            [min_x, min_y, max_x, max_y] if min_x <= max_x && min_y <= max_y => {
+                Ok(BboxParam(Some(Rect::new(
+                    Coord { x: min_x, y: min_y },
+                    Coord { x: max_x, y: max_y },
+                ))))
+            }
+            _ => Err(invalid().into()),
+        }
+    }
+
+    fn default() -> Option<Self> {
+        Some(BboxParam(None))
+    }
+}
+
+/// An optional query param that's rejected if it's there but malformed
+/// For the same reason as `BboxParam`, as Rocket turns a malformed `Option<T>` into `None`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Strict<T>(pub Option<T>);
+
+impl<T> Default for Strict<T> {
+    fn default() -> Self {
+        Strict(None)
+    }
+}
+
+#[rocket::async_trait]
+impl<'v, T: FromFormField<'v>> FromFormField<'v> for Strict<T> {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        T::from_value(field).map(|value| Strict(Some(value)))
+    }
+
+    fn default() -> Option<Self> {
+        Some(Strict(None))
+    }
+}
+
+/// Which activities to include, from the query string
+/// eg `?sport_type=Walk&sport_type=Hike&after=2024-01-01&before=2025-01-01`
+/// `after` is inclusive and `before` exclusive
+#[derive(Debug, Default, FromForm)]
+pub struct Filter {
+    pub sport_type: Vec<SportType>,
+    pub after: Strict<DateParam>,
+    pub before: Strict<DateParam>,
+    pub min_distance: Strict<f64>,
+    pub bbox: BboxParam,
+}
+
+impl Filter {
+    pub fn matches(&self, activity: &Activity) -> bool {
+        let date = activity.start_date.date_naive();
+        if !self.sport_type.is_empty() && !self.sport_type.contains(&activity.sport_type) {
+            return false;
+        }
+        if self.after.0.is_some_and(|DateParam(after)| date < after) {
+            return false;
+        }
+        if self
+            .before
+            .0
+            .is_some_and(|DateParam(before)| date >= before)
+        {
+            return false;
+        }
+        if self.min_distance.0.is_some_and(|d| activity.distance < d) {
+            return false;
+        }
+        if let BboxParam(Some(bbox)) = self.bbox {
+            return activity
+                .linestring
+                .as_ref()
+                .is_some_and(|ls| ls.intersects(&bbox));
+        }
+        true
+    }
 }
 
 pub fn ts_to_dt(timestamp: i32) -> NaiveDateTime {
@@ -69,14 +375,63 @@ pub struct Activity {
     pub start_date: chrono::DateTime<chrono::Utc>,
     pub kudos_count: i32,
     pub average_speed: f64,
-    pub sport_type: String,
+    pub sport_type: SportType,
     pub linestring: Option<LineString>,
 }
 
+/// An activity as stored in the db, keeping the polyline encoded
+#[derive(Debug, Queryable, Selectable, Insertable)]
+#[diesel(table_name = crate::schema::activities)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct ActivityDb {
+    pub id: i64,
+    pub user_id: i32,
+    pub name: String,
+    pub distance: f64,
+    pub moving_time: i64,
+    pub elapsed_time: i64,
+    pub start_date: i64,
+    pub kudos_count: i32,
+    pub average_speed: f64,
+    pub sport_type: String,
+    pub summary_polyline: Option<String>,
+    pub min_lng: Option<f64>,
+    pub min_lat: Option<f64>,
+    pub max_lng: Option<f64>,
+    pub max_lat: Option<f64>,
+}
+
+impl ActivityDb {
+    pub fn from_response(user_id: i32, obj: &ActivityResponse) -> ActivityDb {
+        let bbox =
+            decode_polyline(obj.map.summary_polyline.clone()).and_then(|ls| ls.bounding_rect());
+        ActivityDb {
+            id: obj.id,
+            user_id,
+            name: obj.name.clone(),
+            distance: obj.distance,
+            moving_time: obj.moving_time,
+            elapsed_time: obj.elapsed_time,
+            start_date: obj.start_date.timestamp(),
+            kudos_count: obj.kudos_count,
+            average_speed: obj.average_speed,
+            sport_type: obj.sport_type.to_string(),
+            summary_polyline: obj.map.summary_polyline.clone(),
+            min_lng: bbox.map(|r| r.min().x),
+            min_lat: bbox.map(|r| r.min().y),
+            max_lng: bbox.map(|r| r.max().x),
+            max_lat: bbox.map(|r| r.max().y),
+        }
+    }
+}
+
+fn decode_polyline(poly: Option<String>) -> Option<LineString> {
+    poly.map(|poly| polyline::decode_polyline(&poly, 5).unwrap())
+}
+
 impl Activity {
     pub fn from_response(obj: ActivityResponse) -> Activity {
-        let poly = obj.map.summary_polyline;
-        let linestring = poly.map(|poly| polyline::decode_polyline(&poly, 5).unwrap());
+        let linestring = decode_polyline(obj.map.summary_polyline);
         Activity {
             id: obj.id,
             name: obj.name,
@@ -90,10 +445,30 @@ impl Activity {
             linestring,
         }
     }
+    pub fn from_db(obj: ActivityDb) -> Activity {
+        let linestring = decode_polyline(obj.summary_polyline);
+        Activity {
+            id: obj.id,
+            name: obj.name,
+            distance: obj.distance,
+            moving_time: obj.moving_time,
+            elapsed_time: obj.elapsed_time,
+            start_date: DateTime::from_timestamp(obj.start_date, 0).unwrap(),
+            kudos_count: obj.kudos_count,
+            average_speed: obj.average_speed,
+            sport_type: SportType::from(obj.sport_type),
+            linestring,
+        }
+    }
     pub fn to_properties(&self) -> Option<JsonObject> {
         let mut value = serde_json::to_value(self).unwrap();
         if let JsonValue::Object(ref mut obj) = value {
             obj.remove("linestring");
+            let category = self.sport_type.category();
+            obj.insert(
+                "category".to_string(),
+                serde_json::to_value(category).unwrap(),
+            );
             Some(obj.clone())
         } else {
             None
@@ -103,8 +478,9 @@ impl Activity {
 
 #[cfg(test)]
 mod tests {
+    use crate::geo::fixture_activities;
     use crate::strava;
-    use chrono::{NaiveDate, NaiveTime};
+    use chrono::NaiveTime;
 
     use super::*;
 
@@ -142,7 +518,7 @@ mod tests {
             start_date: dt,
             kudos_count: 0,
             average_speed: 0.0,
-            sport_type: "Ride".to_string(),
+            sport_type: SportType::Ride,
             linestring: None,
         };
         let map = strava::Map {
@@ -157,10 +533,112 @@ mod tests {
             start_date: dt,
             kudos_count: 0,
             average_speed: 0.0,
-            sport_type: "Ride".to_string(),
+            sport_type: SportType::Ride,
             map,
         };
         let got = Activity::from_response(res);
         assert_eq!(want, got);
     }
+
+    #[test]
+    fn activity_db_bbox() {
+        let line = LineString::from(vec![(-3.19, 55.95), (-3.17, 55.94)]);
+        let res = strava::ActivityResponse {
+            id: 0,
+            name: "".to_string(),
+            distance: 0.0,
+            moving_time: 0,
+            elapsed_time: 0,
+            start_date: Utc::now(),
+            kudos_count: 0,
+            average_speed: 0.0,
+            sport_type: SportType::Ride,
+            map: strava::Map {
+                summary_polyline: Some(polyline::encode_coordinates(line, 5).unwrap()),
+            },
+        };
+        let got = ActivityDb::from_response(1, &res);
+        assert_eq!(got.min_lng, Some(-3.19));
+        assert_eq!(got.max_lat, Some(55.95));
+
+        let res = strava::ActivityResponse {
+            map: strava::Map {
+                summary_polyline: None,
+            },
+            ..res
+        };
+        assert_eq!(ActivityDb::from_response(1, &res).min_lng, None);
+    }
+
+    #[test]
+    fn test_bbox_param() {
+        fn parse(q: &str) -> Result<BboxParam, rocket::form::Errors<'_>> {
+            rocket::form::Form::<Filter>::parse(q).map(|f| f.bbox)
+        }
+        let want = Rect::new(Coord { x: -3.4, y: 55.8 }, Coord { x: -3.0, y: 56.1 });
+        assert_eq!(
+            parse("bbox=-3.4,55.8,-3.0,56.1").unwrap(),
+            BboxParam(Some(want))
+        );
+        assert!(parse("bbox=1,2,x,3,4").is_err());
+        assert!(parse("bbox=1,2,3").is_err());
+        assert!(parse("bbox=1,2,NaN,4").is_err());
+        assert!(parse("bbox=3,2,1,4").is_err());
+        assert_eq!(parse("").unwrap(), BboxParam(None));
+    }
+
+    #[test]
+    fn test_strict_params() {
+        let valid = |q: &str| rocket::form::Form::<Filter>::parse(q).is_ok();
+        let filter =
+            rocket::form::Form::<Filter>::parse("after=2024-01-31&min_distance=5000").unwrap();
+        let date = NaiveDate::from_ymd_opt(2024, 1, 31).unwrap();
+        assert_eq!(filter.after, Strict(Some(DateParam(date))));
+        assert_eq!(filter.before, Strict(None));
+        assert_eq!(filter.min_distance, Strict(Some(5000.0)));
+
+        assert!(!valid("after=garbage"));
+        assert!(!valid("before=2024-13-01"));
+        assert!(!valid("min_distance=abc"));
+        assert!(valid(""));
+    }
+
+    #[test]
+    fn test_filter() {
+        let activities = fixture_activities();
+        let all = <Filter as Default>::default();
+        assert!(activities.iter().all(|a| all.matches(a)));
+
+        let walks = Filter {
+            sport_type: vec![SportType::Walk, SportType::Hike],
+            after: Strict(Some(DateParam(
+                NaiveDate::from_ymd_opt(2024, 3, 1).unwrap(),
+            ))),
+            before: Strict(Some(DateParam(
+                NaiveDate::from_ymd_opt(2024, 9, 1).unwrap(),
+            ))),
+            ..Default::default()
+        };
+        let got: Vec<&Activity> = activities.iter().filter(|a| walks.matches(a)).collect();
+        assert!(!got.is_empty());
+        for a in got {
+            assert!(a.sport_type == SportType::Walk || a.sport_type == SportType::Hike);
+            assert!(a.start_date.date_naive() >= NaiveDate::from_ymd_opt(2024, 3, 1).unwrap());
+            assert!(a.start_date.date_naive() < NaiveDate::from_ymd_opt(2024, 9, 1).unwrap());
+        }
+
+        // Around Edinburgh, and long enough
+        let bbox = Rect::new(Coord { x: -3.4, y: 55.8 }, Coord { x: -3.0, y: 56.1 });
+        let edinburgh = Filter {
+            min_distance: Strict(Some(3000.0)),
+            bbox: BboxParam(Some(bbox)),
+            ..Default::default()
+        };
+        let got: Vec<&Activity> = activities.iter().filter(|a| edinburgh.matches(a)).collect();
+        assert!(!got.is_empty());
+        for a in got {
+            assert!(a.distance >= 3000.0);
+            assert!(a.linestring.as_ref().unwrap().intersects(&bbox));
+        }
+    }
 }
@@ -0,0 +1,124 @@
+use geo::{Coord, HaversineDistance, LineString, Point};
+use h3o::CellIndex;
+
+use crate::h3;
+use crate::models::{Activity, PrivacyZoneDb};
+
+/// A circle around somewhere private, like home
+#[derive(Debug, Clone, PartialEq)]
+pub struct Zone {
+    pub center: Point,
+    /// In metres
+    pub radius: f64,
+    /// Whether cells inside still count towards coverage
+    pub count_cells: bool,
+}
+
+impl From<&PrivacyZoneDb> for Zone {
+    fn from(zone: &PrivacyZoneDb) -> Self {
+        Zone {
+            center: Point::new(zone.lng, zone.lat),
+            radius: zone.radius,
+            count_cells: zone.count_cells,
+        }
+    }
+}
+
+impl Zone {
+    pub fn contains(&self, point: Point) -> bool {
+        self.center.haversine_distance(&point) <= self.radius
+    }
+}
+
+fn in_any(zones: &[Zone], c: &Coord) -> bool {
+    zones.iter().any(|z| z.contains(Point::from(*c)))
+}
+
+/// Cut off the start and end of a line for as long as they're inside a zone
+/// Like Strava, only the ends are hidden, as that's where someone lives or works
+/// Returns None if there's nothing left
+pub fn trim(linestring: &LineString, zones: &[Zone]) -> Option<LineString> {
+    let coords = &linestring.0;
+    let start = coords.iter().position(|c| !in_any(zones, c))?;
+    let end = coords.iter().rposition(|c| !in_any(zones, c))?;
+    if end <= start {
+        return None;
+    }
+    Some(LineString::new(coords[start..=end].to_vec()))
+}
+
+/// Trim every activity's line, for anything that leaves the server
+pub fn apply(activities: Vec<Activity>, zones: &[Zone]) -> Vec<Activity> {
+    if zones.is_empty() {
+        return activities;
+    }
+    activities
+        .into_iter()
+        .map(|a| Activity {
+            linestring: a.linestring.and_then(|ls| trim(&ls, zones)),
+            ..a
+        })
+        .collect()
+}
+
+/// Drop cells whose centre is in a zone that shouldn't count towards coverage
+pub fn mask_cells(cells: Vec<CellIndex>, zones: &[Zone]) -> Vec<CellIndex> {
+    let hidden: Vec<&Zone> = zones.iter().filter(|z| !z.count_cells).collect();
+    if hidden.is_empty() {
+        return cells;
+    }
+    cells
+        .into_iter()
+        .filter(|c| {
+            let center = h3::center(*c);
+            !hidden.iter().any(|z| z.contains(center))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use geo::line_string;
+    use h3o::LatLng;
+
+    use super::*;
+
+    fn zone(count_cells: bool) -> Zone {
+        Zone {
+            center: Point::new(-0.1276, 51.5072),
+            radius: 200.0,
+            count_cells,
+        }
+    }
+
+    #[test]
+    fn test_trim() {
+        // Starts at the zone centre, leaves it, and comes back
+        let ls = line_string![
+            (x: -0.1276, y: 51.5072),
+            (x: -0.1270, y: 51.5075),
+            (x: -0.1200, y: 51.5100),
+            (x: -0.1150, y: 51.5120),
+            (x: -0.1275, y: 51.5073),
+        ];
+        let got = trim(&ls, &[zone(true)]).unwrap();
+        let want = line_string![(x: -0.1200, y: 51.5100), (x: -0.1150, y: 51.5120)];
+        assert_eq!(got, want);
+
+        assert_eq!(trim(&ls, &[]), Some(ls.clone()));
+
+        let inside = line_string![(x: -0.1276, y: 51.5072), (x: -0.1270, y: 51.5075)];
+        assert!(trim(&inside, &[zone(true)]).is_none());
+    }
+
+    #[test]
+    fn test_mask_cells() {
+        let inside = LatLng::new(51.5072, -0.1276)
+            .unwrap()
+            .to_cell(h3::RESOLUTION);
+        let outside = LatLng::new(51.6, -0.2).unwrap().to_cell(h3::RESOLUTION);
+        let cells = vec![inside, outside];
+        assert_eq!(mask_cells(cells.clone(), &[zone(true)]), cells);
+        assert_eq!(mask_cells(cells, &[zone(false)]), vec![outside]);
+    }
+}
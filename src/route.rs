@@ -0,0 +1,214 @@
+use std::collections::HashSet;
+
+use geo::{HaversineDistance, LineString, Point};
+use geojson::{Feature, GeoJson, Geometry, JsonObject};
+use h3o::{CellIndex, LatLng};
+
+use crate::error;
+use crate::h3::{self, RESOLUTION};
+use crate::privacy::{self, Zone};
+
+/// Longest loop we'll suggest, in metres, as the search gets slow with the area
+pub const MAX_DISTANCE: f64 = 200_000.0;
+
+/// Whether `start` is a real place and `distance` is worth searching for
+pub fn is_valid(start: Point, distance: f64) -> bool {
+    (-90.0..=90.0).contains(&start.y())
+        && (-180.0..=180.0).contains(&start.x())
+        && distance > 0.0
+        && distance <= MAX_DISTANCE
+}
+
+/// A suggested loop through unvisited cells, as straight lines between cell centres
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    /// The cells to visit, in order
+    pub cells: Vec<CellIndex>,
+    /// From the start, through each cell centre and back to the start
+    pub line: LineString,
+    /// Length of the line in metres
+    pub distance: f64,
+}
+
+/// Unvisited cells next to visited ones, within `radius` metres of `start`
+/// If nothing has been visited yet, it's the cells around the start instead
+/// Cells in privacy zones that don't count are never worth going to
+fn frontier(visited: &[CellIndex], start: Point, radius: f64, zones: &[Zone]) -> Vec<CellIndex> {
+    let visited_set: HashSet<CellIndex> = visited.iter().copied().collect();
+    let mut cells: Vec<CellIndex> = if visited.is_empty() {
+        let Ok(ll) = LatLng::new(start.y(), start.x()) else {
+            return vec![];
+        };
+        ll.to_cell(RESOLUTION).grid_disk(1)
+    } else {
+        visited
+            .iter()
+            .flat_map(|c| c.grid_disk::<Vec<_>>(1))
+            .filter(|c| !visited_set.contains(c))
+            .collect()
+    };
+    cells.sort();
+    cells.dedup();
+    cells.retain(|c| h3::center(*c).haversine_distance(&start) <= radius);
+    privacy::mask_cells(cells, zones)
+}
+
+/// Greedily build a loop from `start` of at most `distance` metres, always going
+/// to the nearest frontier cell that still leaves enough distance to get back
+pub fn suggest(visited: &[CellIndex], start: Point, distance: f64, zones: &[Zone]) -> Route {
+    let mut candidates: Vec<(CellIndex, Point)> = frontier(visited, start, distance / 2.0, zones)
+        .into_iter()
+        .map(|c| (c, h3::center(c)))
+        .collect();
+
+    let mut cells: Vec<CellIndex> = Vec::new();
+    let mut points: Vec<Point> = vec![start];
+    let mut travelled = 0.0;
+    let mut current = start;
+    loop {
+        let next = candidates
+            .iter()
+            .enumerate()
+            .map(|(i, (_, p))| {
+                (
+                    i,
+                    current.haversine_distance(p),
+                    p.haversine_distance(&start),
+                )
+            })
+            .filter(|(_, there, back)| travelled + there + back <= distance)
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+        let Some((i, there, _)) = next else {
+            break;
+        };
+        let (cell, point) = candidates.swap_remove(i);
+        travelled += there;
+        current = point;
+        cells.push(cell);
+        points.push(point);
+    }
+    travelled += current.haversine_distance(&start);
+    points.push(start);
+
+    Route {
+        cells,
+        line: LineString::from(points),
+        distance: travelled,
+    }
+}
+
+impl Route {
+    /// A single GeoJSON Feature of the loop, with the cells (as hex) and distance as properties
+    pub fn to_geojson(&self) -> GeoJson {
+        let mut properties = JsonObject::new();
+        let cells: Vec<String> = self.cells.iter().map(|c| format!("{:x}", c)).collect();
+        properties.insert("cells".to_string(), cells.into());
+        properties.insert("distance".to_string(), self.distance.into());
+        let feature = Feature {
+            geometry: Some(Geometry::from(&self.line)),
+            properties: Some(properties),
+            bbox: None,
+            id: None,
+            foreign_members: None,
+        };
+        feature.into()
+    }
+
+    /// The loop as a GPX route, for loading onto a GPS device
+    pub fn to_gpx(&self) -> Result<String, error::Error> {
+        let mut route = gpx::Route::new();
+        route.name = Some("hexy suggestion".to_string());
+        route.points = self.line.points().map(gpx::Waypoint::new).collect();
+        let gpx = gpx::Gpx {
+            version: gpx::GpxVersion::Gpx11,
+            creator: Some("hexy".to_string()),
+            routes: vec![route],
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        gpx::write(&gpx, &mut buf)?;
+        Ok(String::from_utf8(buf)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn visited(start: Point) -> Vec<CellIndex> {
+        let ll = LatLng::new(start.y(), start.x()).unwrap();
+        let mut cells: Vec<CellIndex> = ll.to_cell(RESOLUTION).grid_disk(2);
+        cells.sort();
+        cells
+    }
+
+    #[test]
+    fn test_is_valid() {
+        let start = Point::new(-0.1276, 51.5072);
+        assert!(is_valid(start, 5000.0));
+        assert!(is_valid(start, MAX_DISTANCE));
+        assert!(!is_valid(start, 1e9));
+        assert!(!is_valid(start, 0.0));
+        assert!(!is_valid(start, f64::NAN));
+        assert!(!is_valid(Point::new(-0.1276, 91.0), 5000.0));
+        assert!(!is_valid(Point::new(f64::INFINITY, 51.5), 5000.0));
+    }
+
+    #[test]
+    fn test_suggest() {
+        let start = Point::new(-0.1276, 51.5072);
+        let visited = visited(start);
+        let route = suggest(&visited, start, 5_000.0, &[]);
+
+        assert!(!route.cells.is_empty());
+        assert!(route.distance <= 5_000.0);
+        assert!(route.cells.iter().all(|c| !visited.contains(c)));
+        // Starts and ends at the start, through every cell centre
+        assert_eq!(route.line.0.len(), route.cells.len() + 2);
+        assert_eq!(route.line.points().next(), Some(start));
+        assert_eq!(route.line.points().next_back(), Some(start));
+    }
+
+    #[test]
+    fn test_suggest_too_short() {
+        let start = Point::new(-0.1276, 51.5072);
+        let route = suggest(&visited(start), start, 100.0, &[]);
+        assert!(route.cells.is_empty());
+        assert_eq!(route.distance, 0.0);
+    }
+
+    #[test]
+    fn test_suggest_nothing_visited() {
+        let start = Point::new(-0.1276, 51.5072);
+        let route = suggest(&[], start, 2_000.0, &[]);
+        assert!(!route.cells.is_empty());
+    }
+
+    #[test]
+    fn test_suggest_avoids_zones() {
+        let start = Point::new(-0.1276, 51.5072);
+        let zones = [Zone {
+            center: start,
+            radius: 2_000.0,
+            count_cells: false,
+        }];
+        // Visited around the start and a few km east of it
+        let mut cells = visited(start);
+        cells.extend(visited(Point::new(-0.085, 51.5072)));
+        let route = suggest(&cells, start, 10_000.0, &zones);
+        assert!(!route.cells.is_empty());
+        assert!(route
+            .cells
+            .iter()
+            .all(|c| !zones[0].contains(h3::center(*c))));
+    }
+
+    #[test]
+    fn test_route_gpx() {
+        let start = Point::new(-0.1276, 51.5072);
+        let route = suggest(&visited(start), start, 3_000.0, &[]);
+        let got = gpx::read(route.to_gpx().unwrap().as_bytes()).unwrap();
+        assert_eq!(got.routes.len(), 1);
+        assert_eq!(got.routes[0].linestring(), route.line);
+    }
+}
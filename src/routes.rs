@@ -1,29 +1,49 @@
-use log::info;
+use geojson::GeoJson;
+use h3o::CellIndex;
+use log::warn;
 use rocket::fairing::AdHoc;
+use rocket::form::Form;
 use rocket::fs::{relative, FileServer};
-use rocket::http::{Cookie, CookieJar, SameSite};
+use rocket::http::{ContentType, Cookie, CookieJar, Header, SameSite, Status};
+use rocket::response::stream::{Event as SseEvent, EventStream};
 use rocket::response::Redirect;
 use rocket::serde::json::Json;
-use rocket::{get, routes, uri, Build, Rocket};
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::{get, post, routes, uri, Build, Either, Responder, Rocket, Shutdown, State};
 use rocket_dyn_templates::context;
 use rocket_dyn_templates::Template;
+use serde::Serialize;
+use std::collections::HashSet;
 use std::env;
 
+use crate::compact::{CompactData, MsgPack, Negotiated};
 use crate::db::Db;
 use crate::error;
-use crate::models::{is_dt_past, ts_to_dt, Data, User};
-use crate::{db, geo, h3, strava};
+use crate::events::Events;
+use crate::groups::{Coverage, Ownership, Standing};
+use crate::models::{
+    Activity, Data, Filter, GroupDb, GroupForm, JobDb, JoinForm, NewGroupDb, NewPrivacyZoneDb,
+    PrivacyZoneDb, ShareTokenDb, Target, User, ZoneForm,
+};
+use crate::privacy::Zone;
+use crate::stats::{self, Interval, Stats, Step};
+use crate::{crypto, db, export, geo, groups, h3, jobs, route, strava, sync, tiles};
 
 pub fn build(prep_db: bool) -> Rocket<Build> {
     let mut s = rocket::build()
         .attach(db::Db::fairing())
         .attach(Template::fairing())
+        .manage(Events::default())
+        .manage(sync::Budget::default()) // Strava requests for the scheduler and job worker
         .mount("/static", FileServer::from(relative!("static")))
         .mount("/", routes());
 
     if prep_db {
         s = s
             .attach(AdHoc::try_on_ignite("Migrations", db::migrate)) // Database migrations
+            .attach(sync::fairing()) // Background token refresh and syncs
+            .attach(jobs::fairing()) // Full-history syncs from the job queue
             .attach(AdHoc::on_liftoff("Startup Check", |rocket| {
                 Box::pin(async move {
                     let d = db::Db::get_one(rocket).await.unwrap();
@@ -40,9 +60,36 @@ fn routes() -> Vec<rocket::Route> {
         authed_index,
         unauthed_index,
         get_data,
+        start_sync,
+        get_sync_status,
+        events,
+        get_stats,
+        get_timeline,
+        get_targets,
+        get_route,
+        get_route_gpx,
+        create_group,
+        join_group,
+        leave_group,
+        get_leaderboard,
+        get_territory,
+        get_group_coverage,
+        get_privacy_zones,
+        create_privacy_zone,
+        delete_privacy_zone,
+        create_share,
+        revoke_share,
+        shared_index,
+        shared_data,
+        export_gpx,
+        export_kml,
+        export_account,
+        delete_account,
+        get_tile,
         auth,
         callback,
         logout,
+        disconnect,
         home,
         privacy,
     ]
@@ -58,7 +105,13 @@ fn authed_index(user: User) -> Template {
     let User { id } = user;
     let os_key = env::var("OS_KEY").unwrap();
     let logged_in = true;
-    Template::render("index", context! { id, os_key, logged_in })
+    let read_only = false;
+    // The lines come from tiles
+    let data_url = "/data?lines=false";
+    Template::render(
+        "index",
+        context! { id, os_key, logged_in, read_only, data_url },
+    )
 }
 
 #[get("/", rank = 2)]
@@ -66,49 +119,596 @@ fn unauthed_index() -> Template {
     let id = "";
     let os_key = env::var("OS_KEY").unwrap();
     let logged_in = false;
-    Template::render("index", context! { id, os_key, logged_in })
+    let read_only = false;
+    let data_url = "";
+    Template::render(
+        "index",
+        context! { id, os_key, logged_in, read_only, data_url },
+    )
 }
 
-#[get("/data")]
-async fn get_data(conn: Db, user: User) -> Result<Json<Data>, error::Error> {
+/// A file download, named so that browsers save it rather than display it
+#[derive(Responder)]
+struct Attachment {
+    inner: (ContentType, Vec<u8>),
+    disposition: Header<'static>,
+}
+
+impl Attachment {
+    fn new(content_type: ContentType, filename: &str, body: impl Into<Vec<u8>>) -> Self {
+        let disposition = format!("attachment; filename=\"{}\"", filename);
+        Attachment {
+            inner: (content_type, body.into()),
+            disposition: Header::new("Content-Disposition", disposition),
+        }
+    }
+}
+
+/// Pass `tolerance` (in degrees) or `zoom` to simplify the activity lines
+/// and `compact=true` to get cells compacted to mixed resolutions
+/// (expand them again with h3::uncompact or h3-js `uncompactCells`)
+/// and `lines=false` to leave out the lines, eg for the map which gets them from tiles
+/// Any other params are a `Filter` on which activities (and so cells) to include
+/// Responds with JSON unless MessagePack is requested in the Accept header
+#[get("/data?<tolerance>&<zoom>&<compact>&<lines>&<filter..>")]
+#[allow(clippy::too_many_arguments)]
+async fn get_data(
+    conn: Db,
+    user: User,
+    format: Negotiated,
+    tolerance: Option<f64>,
+    zoom: Option<f64>,
+    compact: Option<bool>,
+    lines: Option<bool>,
+    filter: Filter,
+) -> Result<Either<Json<Data>, MsgPack>, error::Error> {
     let User { id } = user;
+    let token = sync::get_token(&conn, id).await?;
+    let zones = get_zones(&conn, id).await?;
 
-    let user = db::get_user(&conn, id).await?;
-    let expiry = ts_to_dt(user.expires_at);
-    let expired = is_dt_past(expiry);
-
-    // previously I was just getting a token out of the cookie
-    // which was quite elegant, but didn't provide for refreshing...
-    let token = if expired {
-        // get a new token (using refresh_token) if this one expired
-        info!("getting new token for id {}", id);
-        let token_response = strava::StravaClient::default()
-            .get_token(&user.refresh_token, strava::GrantType::Refresh)
-            .await?;
-        db::save_user(&conn, &token_response).await?;
-        token_response.access_token
+    // The first time, the whole history is fetched in the background (see start_sync)
+    // and after that just the latest activities, which is quick enough to wait for
+    // That includes users who had no activities yet when their history was fetched
+    let fetched = db::get_latest_start_date(&conn, id).await?.is_some()
+        || db::has_done_job(&conn, id).await?;
+    if fetched {
+        // Not from the background budget, this is the half left for people using the site
+        sync::sync(&conn, &strava::StravaClient::default(), None, id, &token).await?;
     } else {
-        // otherwise use the current one
-        user.access_token
+        db::enqueue_job(&conn, id, chrono::Utc::now().timestamp()).await?;
+    }
+    let mut activities = db::get_activities(&conn, id).await?;
+    // Before filtering, so that cells count as new only the first time ever
+    let attributions = h3::attribute(&activities, &zones);
+    activities.retain(|a| filter.matches(a));
+    // Cells come from the whole lines, but nothing else sees the trimmed-off ends
+    let mut sport_cells = h3::polyfill_by_category(&activities);
+    for cells in sport_cells.values_mut() {
+        *cells = crate::privacy::mask_cells(std::mem::take(cells), &zones);
+    }
+    let mut cells = h3::union(sport_cells.values());
+    let activities = crate::privacy::apply(activities, &zones);
+    let regions = geo::get_regions(&activities, &geo::ClusterConfig::default());
+    let home = regions
+        .first()
+        .cloned()
+        .or_else(|| geo::latest_region(&activities));
+    if compact.unwrap_or(false) {
+        cells = h3::compact(cells)?;
+        for cells in sport_cells.values_mut() {
+            *cells = h3::compact(std::mem::take(cells))?;
+        }
+    }
+
+    let tolerance = tolerance.or(zoom.map(geo::zoom_to_tolerance));
+    let activities = match tolerance {
+        _ if !lines.unwrap_or(true) => activities
+            .into_iter()
+            .map(|a| Activity {
+                linestring: None,
+                ..a
+            })
+            .collect(),
+        Some(tolerance) => geo::simplify_all(activities, tolerance),
+        None => activities,
     };
 
-    let activities = strava::StravaClient::default()
-        .get_activities(&token)
-        .await?;
-    let activities = geo::decode_all(activities);
-    let centroid = geo::get_useful_centroid(&activities);
-    let cells = h3::polyfill_all(&activities);
-    let cells: Vec<String> = cells
+    if format.msgpack {
+        let data = CompactData::new(
+            activities,
+            &attributions,
+            &cells,
+            home.as_ref(),
+            regions,
+            &sport_cells,
+        );
+        return Ok(Either::Right(MsgPack::new(&data, format.encoding)?));
+    }
+
+    let to_hex = |cells: &[CellIndex]| -> Vec<String> {
+        cells
+            .iter()
+            .map(|cell_index| format!("{:x}", cell_index))
+            .collect()
+    };
+    let cells = to_hex(&cells);
+    let sport_cells = sport_cells
         .iter()
-        .map(|cell_index| format!("{:x}", cell_index))
+        .map(|(category, cells)| (*category, to_hex(cells)))
         .collect();
-
-    let activities = geo::to_geojson(activities);
-    Ok(Json(Data {
+    let activities = geo::to_geojson(activities, &attributions);
+    Ok(Either::Left(Json(Data {
         activities: Some(activities),
         cells,
-        centroid,
-    }))
+        centroid: home.as_ref().map(|h| h.centroid),
+        bbox: home.as_ref().map(|h| h.bbox),
+        regions,
+        sport_cells,
+    })))
+}
+
+/// Queue a sync of the user's whole Strava history, eg to pick up edited activities
+/// Poll `/sync/status` to see how it's going
+#[post("/sync")]
+async fn start_sync(conn: Db, user: User) -> Result<Json<JobDb>, error::Error> {
+    let job = db::enqueue_job(&conn, user.id, chrono::Utc::now().timestamp()).await?;
+    Ok(Json(job))
+}
+
+/// The user's latest sync job, with `fetched` and (roughly) `total` activities
+#[get("/sync/status")]
+async fn get_sync_status(conn: Db, user: User) -> Result<Option<Json<JobDb>>, error::Error> {
+    Ok(db::get_latest_job(&conn, user.id).await?.map(Json))
+}
+
+/// Live `progress`, `cells`, `done` and `failed` events from the user's syncs
+/// Events sent while nobody is connected are gone, so check `/sync/status` first
+#[get("/events")]
+fn events(user: User, events: &State<Events>, mut end: Shutdown) -> EventStream![] {
+    let mut rx = events.subscribe();
+    EventStream! {
+        loop {
+            let (id, event) = select! {
+                msg = rx.recv() => match msg {
+                    Ok(msg) => msg,
+                    Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+                _ = &mut end => break,
+            };
+            if id == user.id {
+                yield SseEvent::json(&event).event(event.name());
+            }
+        }
+    }
+}
+
+/// Coverage statistics from the stored activities
+#[get("/stats")]
+async fn get_stats(conn: Db, user: User) -> Result<Json<Stats>, error::Error> {
+    let activities = db::get_activities(&conn, user.id).await?;
+    let zones = get_zones(&conn, user.id).await?;
+    Ok(Json(Stats::new(&activities, &zones)))
+}
+
+/// Cells first visited in each `interval` (week, month or year), oldest first
+#[get("/timeline?<interval>")]
+async fn get_timeline(
+    conn: Db,
+    user: User,
+    interval: Option<Interval>,
+) -> Result<Json<Vec<Step>>, error::Error> {
+    let activities = db::get_activities(&conn, user.id).await?;
+    let zones = get_zones(&conn, user.id).await?;
+    let interval = interval.unwrap_or(Interval::Month);
+    Ok(Json(stats::timeline(&activities, interval, &zones)))
+}
+
+/// Unvisited cells with at least `min_neighbours` (default 4) of their
+/// neighbours visited, nearest home first, up to `limit` (default 50) of them
+#[get("/targets?<min_neighbours>&<limit>")]
+async fn get_targets(
+    conn: Db,
+    user: User,
+    min_neighbours: Option<usize>,
+    limit: Option<usize>,
+) -> Result<Json<Vec<Target>>, error::Error> {
+    let activities = db::get_activities(&conn, user.id).await?;
+    let zones = get_zones(&conn, user.id).await?;
+    let cells = crate::privacy::mask_cells(h3::polyfill_all(&activities), &zones);
+    let activities = crate::privacy::apply(activities, &zones);
+    let Some(home) = geo::get_useful_centroid(&activities, &geo::ClusterConfig::default()) else {
+        return Ok(Json(vec![]));
+    };
+    let gaps = h3::find_gaps(&cells, min_neighbours.unwrap_or(4), home.centroid);
+    // Cells that don't count would just show up as gaps
+    let counted: HashSet<CellIndex> =
+        crate::privacy::mask_cells(gaps.iter().map(|gap| gap.cell).collect(), &zones)
+            .into_iter()
+            .collect();
+    let targets = gaps
+        .into_iter()
+        .filter(|gap| counted.contains(&gap.cell))
+        .take(limit.unwrap_or(50))
+        .map(|gap| Target {
+            cell: format!("{:x}", gap.cell),
+            center: h3::center(gap.cell),
+            visited_neighbours: gap.visited_neighbours,
+            distance: gap.distance,
+        })
+        .collect();
+    Ok(Json(targets))
+}
+
+/// Suggest a loop of roughly `distance` metres from `lat`,`lng`
+/// through unvisited cells at the edge of the user's coverage
+/// Returns None if the start or distance are out of range
+async fn suggest_route(
+    conn: &Db,
+    user: User,
+    lat: f64,
+    lng: f64,
+    distance: f64,
+) -> Result<Option<route::Route>, error::Error> {
+    let start = ::geo::Point::new(lng, lat);
+    if !route::is_valid(start, distance) {
+        return Ok(None);
+    }
+    let activities = db::get_activities(conn, user.id).await?;
+    let zones = get_zones(conn, user.id).await?;
+    // The search can take a while, so keep it off the async workers
+    let route = rocket::tokio::task::spawn_blocking(move || {
+        let cells = crate::privacy::mask_cells(h3::polyfill_all(&activities), &zones);
+        route::suggest(&cells, start, distance, &zones)
+    })
+    .await?;
+    Ok(Some(route))
+}
+
+#[get("/route?<lat>&<lng>&<distance>")]
+async fn get_route(
+    conn: Db,
+    user: User,
+    lat: f64,
+    lng: f64,
+    distance: f64,
+) -> Result<Result<Json<GeoJson>, Status>, error::Error> {
+    let Some(route) = suggest_route(&conn, user, lat, lng, distance).await? else {
+        return Ok(Err(Status::BadRequest));
+    };
+    Ok(Ok(Json(route.to_geojson())))
+}
+
+#[get("/route.gpx?<lat>&<lng>&<distance>")]
+async fn get_route_gpx(
+    conn: Db,
+    user: User,
+    lat: f64,
+    lng: f64,
+    distance: f64,
+) -> Result<Result<Attachment, Status>, error::Error> {
+    let Some(route) = suggest_route(&conn, user, lat, lng, distance).await? else {
+        return Ok(Err(Status::BadRequest));
+    };
+    let content_type = ContentType::new("application", "gpx+xml");
+    Ok(Ok(Attachment::new(
+        content_type,
+        "route.gpx",
+        route.to_gpx()?,
+    )))
+}
+
+/// Start a new group, with the creator as its first member
+/// Share the `invite_code` in the response for others to join
+#[post("/groups", data = "<form>")]
+async fn create_group(
+    conn: Db,
+    user: User,
+    form: Form<GroupForm>,
+) -> Result<Json<GroupDb>, error::Error> {
+    let group = NewGroupDb {
+        name: form.into_inner().name,
+        invite_code: groups::invite_code(),
+        owner_id: user.id,
+        created_at: chrono::Utc::now().timestamp(),
+    };
+    let group = db::create_group(&conn, group).await?;
+    Ok(Json(group))
+}
+
+/// Join a group with its invite code
+/// This is the only way into a group, so nobody is added without opting in
+#[post("/groups/join", data = "<form>")]
+async fn join_group(
+    conn: Db,
+    user: User,
+    form: Form<JoinForm>,
+) -> Result<Option<Json<GroupDb>>, error::Error> {
+    let now = chrono::Utc::now().timestamp();
+    let group = db::join_group(&conn, user.id, form.into_inner().code, now).await?;
+    Ok(group.map(Json))
+}
+
+#[post("/groups/<id>/leave")]
+async fn leave_group(conn: Db, user: User, id: i32) -> Result<Status, error::Error> {
+    match db::leave_group(&conn, user.id, id).await? {
+        0 => Ok(Status::NotFound),
+        _ => Ok(Status::NoContent),
+    }
+}
+
+/// Members ranked by visited cells, largest cluster and new cells this month
+/// from their cells as of their last sync
+/// Only visible to members of the group
+#[get("/groups/<id>/leaderboard")]
+async fn get_leaderboard(
+    conn: Db,
+    user: User,
+    id: i32,
+) -> Result<Option<Json<Vec<Standing>>>, error::Error> {
+    if db::get_group(&conn, user.id, id).await?.is_none() {
+        return Ok(None);
+    }
+    let now = chrono::Utc::now();
+    let mut standings: Vec<Standing> = Vec::new();
+    for member in db::get_group_members(&conn, id).await? {
+        let cells = db::get_cells(&conn, member).await?;
+        standings.push(Standing::new(member, &cells, now));
+    }
+    Ok(Some(Json(groups::leaderboard(standings))))
+}
+
+/// Every cell visited by a member, with who owns it
+/// `by=first` (the default) for whoever got there first, `by=most` for whoever went most
+#[get("/groups/<id>/territory?<by>")]
+async fn get_territory(
+    conn: Db,
+    user: User,
+    id: i32,
+    by: Option<Ownership>,
+) -> Result<Option<Json<GeoJson>>, error::Error> {
+    if db::get_group(&conn, user.id, id).await?.is_none() {
+        return Ok(None);
+    }
+    let members = db::get_group_members(&conn, id).await?;
+    let mut cells = Vec::with_capacity(members.len());
+    for member in &members {
+        cells.push((*member, db::get_cells(&conn, *member).await?));
+    }
+    let claims = groups::territory(&cells, by.unwrap_or(Ownership::First));
+    Ok(Some(Json(groups::territory_geojson(&claims, &members))))
+}
+
+/// All the cells visited by the group, and how much members overlap
+#[get("/groups/<id>/coverage")]
+async fn get_group_coverage(
+    conn: Db,
+    user: User,
+    id: i32,
+) -> Result<Option<Json<Coverage>>, error::Error> {
+    if db::get_group(&conn, user.id, id).await?.is_none() {
+        return Ok(None);
+    }
+    let members = db::get_group_members(&conn, id).await?;
+    let mut cells = Vec::with_capacity(members.len());
+    for member in members {
+        let mut visited: Vec<CellIndex> = db::get_cells(&conn, member)
+            .await?
+            .iter()
+            .filter_map(|c| c.cell_index())
+            .collect();
+        visited.sort();
+        cells.push((member, visited));
+    }
+    Ok(Some(Json(Coverage::new(&cells))))
+}
+
+/// The user's privacy zones, for trimming their activities
+async fn get_zones(conn: &Db, id: i32) -> Result<Vec<Zone>, error::Error> {
+    let zones = db::get_privacy_zones(conn, id).await?;
+    Ok(zones.iter().map(Zone::from).collect())
+}
+
+#[get("/privacy/zones")]
+async fn get_privacy_zones(conn: Db, user: User) -> Result<Json<Vec<PrivacyZoneDb>>, error::Error> {
+    Ok(Json(db::get_privacy_zones(&conn, user.id).await?))
+}
+
+/// Add a circle that activity lines get trimmed at
+/// Stored cells are recomputed, so shared maps and groups respect `count_cells`
+#[post("/privacy/zones", data = "<form>")]
+async fn create_privacy_zone(
+    conn: Db,
+    user: User,
+    form: Form<ZoneForm>,
+) -> Result<Json<Vec<PrivacyZoneDb>>, error::Error> {
+    let form = form.into_inner();
+    let zone = NewPrivacyZoneDb {
+        user_id: user.id,
+        lat: form.lat,
+        lng: form.lng,
+        radius: form.radius,
+        count_cells: form.count_cells.unwrap_or(true),
+    };
+    db::save_privacy_zone(&conn, zone).await?;
+    db::update_cells(&conn, user.id).await?;
+    Ok(Json(db::get_privacy_zones(&conn, user.id).await?))
+}
+
+#[post("/privacy/zones/<id>/delete")]
+async fn delete_privacy_zone(conn: Db, user: User, id: i32) -> Result<Status, error::Error> {
+    if db::delete_privacy_zone(&conn, user.id, id).await? == 0 {
+        return Ok(Status::NotFound);
+    }
+    db::update_cells(&conn, user.id).await?;
+    Ok(Status::NoContent)
+}
+
+/// A public link to the user's hexagons (see shared_index)
+#[derive(Serialize)]
+struct Share {
+    token: String,
+    url: String,
+}
+
+/// Opt in to sharing, with a new link that replaces any previous one
+/// Only the hash is stored, so this is the only time the token is shown
+#[post("/share")]
+async fn create_share(conn: Db, user: User) -> Result<Json<Share>, error::Error> {
+    let token = crypto::new_token();
+    db::save_share_token(
+        &conn,
+        ShareTokenDb {
+            user_id: user.id,
+            token_hash: crypto::hash_token(&token),
+            created_at: chrono::Utc::now().timestamp(),
+        },
+    )
+    .await?;
+    let url = uri!(shared_index(&token)).to_string();
+    Ok(Json(Share { token, url }))
+}
+
+/// Stop sharing, so the old link no longer works
+#[post("/share/revoke")]
+async fn revoke_share(conn: Db, user: User) -> Result<Status, error::Error> {
+    db::delete_share_token(&conn, user.id).await?;
+    Ok(Status::NoContent)
+}
+
+/// Read-only map of someone's hexagons, without any of their activities
+#[get("/share/<token>")]
+async fn shared_index(conn: Db, token: &str) -> Result<Option<Template>, error::Error> {
+    if db::get_share_user(&conn, crypto::hash_token(token))
+        .await?
+        .is_none()
+    {
+        return Ok(None);
+    }
+    let id = "";
+    let os_key = env::var("OS_KEY").unwrap();
+    let logged_in = false;
+    let read_only = true;
+    let data_url = uri!(shared_data(token)).to_string();
+    Ok(Some(Template::render(
+        "index",
+        context! { id, os_key, logged_in, read_only, data_url },
+    )))
+}
+
+/// Just the visited cells, and a bbox around them rather than any activity
+#[get("/share/<token>/data")]
+async fn shared_data(conn: Db, token: &str) -> Result<Option<Json<Data>>, error::Error> {
+    let Some(id) = db::get_share_user(&conn, crypto::hash_token(token)).await? else {
+        return Ok(None);
+    };
+    let mut cells: Vec<CellIndex> = db::get_cells(&conn, id)
+        .await?
+        .iter()
+        .filter_map(|c| c.cell_index())
+        .collect();
+    cells.sort();
+    let bbox = h3::bbox(&cells);
+    Ok(Some(Json(Data {
+        activities: None,
+        cells: cells.iter().map(|c| format!("{:x}", c)).collect(),
+        centroid: bbox.map(|b| b.center().into()),
+        bbox,
+        regions: vec![],
+        sport_cells: Default::default(),
+    })))
+}
+
+#[get("/export/activities.gpx")]
+async fn export_gpx(conn: Db, user: User) -> Result<Attachment, error::Error> {
+    let activities = db::get_activities(&conn, user.id).await?;
+    let activities = crate::privacy::apply(activities, &get_zones(&conn, user.id).await?);
+    let gpx = export::to_gpx(&activities)?;
+    let content_type = ContentType::new("application", "gpx+xml");
+    Ok(Attachment::new(content_type, "activities.gpx", gpx))
+}
+
+#[get("/export/activities.kml")]
+async fn export_kml(conn: Db, user: User) -> Result<Attachment, error::Error> {
+    let activities = db::get_activities(&conn, user.id).await?;
+    let activities = crate::privacy::apply(activities, &get_zones(&conn, user.id).await?);
+    let kml = export::to_kml(&activities)?;
+    let content_type = ContentType::new("application", "vnd.google-earth.kml+xml");
+    Ok(Attachment::new(content_type, "activities.kml", kml))
+}
+
+/// A zip of everything stored about the user
+#[get("/account/export")]
+async fn export_account(conn: Db, user: User) -> Result<Attachment, error::Error> {
+    let data = export::AccountData {
+        user: db::get_user(&conn, user.id).await?,
+        activities: db::get_activities(&conn, user.id).await?,
+        cells: db::get_cells(&conn, user.id).await?,
+        groups: db::get_user_groups(&conn, user.id).await?,
+        privacy_zones: db::get_privacy_zones(&conn, user.id).await?,
+        share: db::get_share_token(&conn, user.id).await?,
+        sync_jobs: db::get_sync_jobs(&conn, user.id).await?,
+        jobs: db::get_jobs(&conn, user.id).await?,
+    };
+    Ok(Attachment::new(
+        ContentType::ZIP,
+        "hexy.zip",
+        data.to_zip()?,
+    ))
+}
+
+/// Revoke our access on Strava, just logging it if that fails
+/// so that the caller can carry on and forget the tokens anyway
+async fn deauthorize(conn: &Db, id: i32) {
+    let deauthorized = match sync::get_token(conn, id).await {
+        Ok(token) => strava::StravaClient::default().deauthorize(&token).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = deauthorized {
+        warn!("failed to deauthorize user {} on Strava: {}", id, e.0);
+    }
+}
+
+/// Delete the user and everything stored about them, revoke our access on Strava and log out
+#[post("/account/delete")]
+async fn delete_account(
+    conn: Db,
+    user: User,
+    jar: &CookieJar<'_>,
+) -> Result<Redirect, error::Error> {
+    deauthorize(&conn, user.id).await;
+    db::delete_user(&conn, user.id).await?;
+    jar.remove_private("id");
+    Ok(Redirect::to(uri!(unauthed_index)))
+}
+
+/// The `y` segment includes the extension, eg `/tiles/12/2046/1362.mvt`
+#[get("/tiles/<z>/<x>/<y>")]
+async fn get_tile(
+    conn: Db,
+    user: User,
+    z: u32,
+    x: u32,
+    y: &str,
+) -> Result<Option<(ContentType, Vec<u8>)>, error::Error> {
+    let id = y
+        .strip_suffix(".mvt")
+        .and_then(|y| y.parse().ok())
+        .and_then(|y| tiles::TileId::new(z, x, y));
+    let Some(id) = id else {
+        return Ok(None);
+    };
+    let activities = db::get_activities_within(&conn, user.id, id.bounds()).await?;
+    // Only the stored cells that could be in the tile, unless it's most of the world
+    let cells = match h3::ranges_within(id.near()) {
+        Some(ranges) => db::get_cells_in(&conn, user.id, ranges).await?,
+        None => db::get_cells(&conn, user.id).await?,
+    };
+    let cells: Vec<CellIndex> = cells.iter().filter_map(|c| c.cell_index()).collect();
+    let zones = get_zones(&conn, user.id).await?;
+    let tile = tiles::encode(&id, activities, &cells, &zones)?;
+    let content_type = ContentType::new("application", "vnd.mapbox-vector-tile");
+    Ok(Some((content_type, tile)))
 }
 
 #[get("/auth")]
@@ -140,6 +740,16 @@ fn logout(jar: &CookieJar<'_>) -> Redirect {
     Redirect::to(uri!(unauthed_index))
 }
 
+/// Log out and revoke our access on Strava, forgetting the stored tokens
+/// Activities and cells are kept until the user logs in again (or deletes their account)
+#[post("/disconnect")]
+async fn disconnect(conn: Db, user: User, jar: &CookieJar<'_>) -> Result<Redirect, error::Error> {
+    deauthorize(&conn, user.id).await;
+    db::clear_tokens(&conn, user.id).await?;
+    jar.remove_private("id");
+    Ok(Redirect::to(uri!(unauthed_index)))
+}
+
 #[get("/home")]
 fn home() -> Template {
     Template::render("home", ())
@@ -1,5 +1,98 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    activities (id) {
+        id -> BigInt,
+        user_id -> Integer,
+        name -> Text,
+        distance -> Double,
+        moving_time -> BigInt,
+        elapsed_time -> BigInt,
+        start_date -> BigInt,
+        kudos_count -> Integer,
+        average_speed -> Double,
+        sport_type -> Text,
+        summary_polyline -> Nullable<Text>,
+        min_lng -> Nullable<Double>,
+        min_lat -> Nullable<Double>,
+        max_lng -> Nullable<Double>,
+        max_lat -> Nullable<Double>,
+    }
+}
+
+diesel::table! {
+    cells (user_id, cell) {
+        user_id -> Integer,
+        cell -> BigInt,
+        first_visited -> BigInt,
+        visits -> Integer,
+    }
+}
+
+diesel::table! {
+    group_members (group_id, user_id) {
+        group_id -> Integer,
+        user_id -> Integer,
+        joined_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    groups (id) {
+        id -> Integer,
+        name -> Text,
+        invite_code -> Text,
+        owner_id -> Integer,
+        created_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    jobs (id) {
+        id -> Integer,
+        user_id -> Integer,
+        status -> Text,
+        attempts -> Integer,
+        last_error -> Nullable<Text>,
+        fetched -> Integer,
+        total -> Nullable<Integer>,
+        created_at -> BigInt,
+        updated_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    privacy_zones (id) {
+        id -> Integer,
+        user_id -> Integer,
+        lat -> Double,
+        lng -> Double,
+        radius -> Double,
+        count_cells -> Bool,
+    }
+}
+
+diesel::table! {
+    share_tokens (user_id) {
+        user_id -> Integer,
+        token_hash -> Text,
+        created_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    sync_jobs (id) {
+        id -> Integer,
+        user_id -> Integer,
+        kind -> Text,
+        status -> Text,
+        started_at -> BigInt,
+        finished_at -> BigInt,
+        activities -> Integer,
+        error -> Nullable<Text>,
+    }
+}
+
 diesel::table! {
     users (id) {
         id -> Integer,
@@ -8,3 +101,25 @@ diesel::table! {
         expires_at -> Integer,
     }
 }
+
+diesel::joinable!(activities -> users (user_id));
+diesel::joinable!(cells -> users (user_id));
+diesel::joinable!(group_members -> groups (group_id));
+diesel::joinable!(group_members -> users (user_id));
+diesel::joinable!(groups -> users (owner_id));
+diesel::joinable!(jobs -> users (user_id));
+diesel::joinable!(privacy_zones -> users (user_id));
+diesel::joinable!(share_tokens -> users (user_id));
+diesel::joinable!(sync_jobs -> users (user_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    activities,
+    cells,
+    group_members,
+    groups,
+    jobs,
+    privacy_zones,
+    share_tokens,
+    sync_jobs,
+    users,
+);
@@ -0,0 +1,169 @@
+use std::fmt;
+
+use rocket::form::{self, FromFormField, ValueField};
+use serde::{Deserialize, Serialize};
+
+/// Defines SportType with a variant for each of Strava's sport types
+/// (named exactly as Strava does) plus an Unknown fallback for new ones
+macro_rules! sport_types {
+    ($($name:ident),* $(,)?) => {
+        /// Strava's `sport_type`, see https://developers.strava.com/docs/reference/#api-models-SportType
+        #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+        #[serde(from = "String", into = "String")]
+        pub enum SportType {
+            $($name,)*
+            Unknown(String),
+        }
+
+        impl SportType {
+            pub fn as_str(&self) -> &str {
+                match self {
+                    $(SportType::$name => stringify!($name),)*
+                    SportType::Unknown(s) => s,
+                }
+            }
+        }
+
+        impl From<String> for SportType {
+            fn from(s: String) -> Self {
+                match s.as_str() {
+                    $(stringify!($name) => SportType::$name,)*
+                    _ => SportType::Unknown(s),
+                }
+            }
+        }
+    };
+}
+
+sport_types!(
+    AlpineSki,
+    BackcountrySki,
+    Badminton,
+    Canoeing,
+    Crossfit,
+    EBikeRide,
+    Elliptical,
+    EMountainBikeRide,
+    Golf,
+    GravelRide,
+    Handcycle,
+    HighIntensityIntervalTraining,
+    Hike,
+    IceSkate,
+    InlineSkate,
+    Kayaking,
+    Kitesurf,
+    MountainBikeRide,
+    NordicSki,
+    Pickleball,
+    Pilates,
+    Racquetball,
+    Ride,
+    RockClimbing,
+    RollerSki,
+    Rowing,
+    Run,
+    Sail,
+    Skateboard,
+    Snowboard,
+    Snowshoe,
+    Soccer,
+    Squash,
+    StairStepper,
+    StandUpPaddling,
+    Surfing,
+    Swim,
+    TableTennis,
+    Tennis,
+    TrailRun,
+    Velomobile,
+    VirtualRide,
+    VirtualRow,
+    VirtualRun,
+    Walk,
+    WeightTraining,
+    Wheelchair,
+    Windsurf,
+    Workout,
+    Yoga,
+);
+
+impl SportType {
+    pub fn category(&self) -> Category {
+        use SportType::*;
+        match self {
+            Ride | EBikeRide | EMountainBikeRide | GravelRide | MountainBikeRide => Category::Ride,
+            Run | TrailRun => Category::Run,
+            Walk | Hike => Category::Walk,
+            Swim => Category::Swim,
+            Canoeing | Kayaking | Kitesurf | Rowing | Sail | Surfing => Category::Water,
+            _ => Category::Other,
+        }
+    }
+}
+
+impl From<&str> for SportType {
+    fn from(s: &str) -> Self {
+        SportType::from(s.to_string())
+    }
+}
+
+impl From<SportType> for String {
+    fn from(s: SportType) -> Self {
+        s.as_str().to_string()
+    }
+}
+
+impl fmt::Display for SportType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[rocket::async_trait]
+impl<'v> FromFormField<'v> for SportType {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        Ok(SportType::from(field.value))
+    }
+}
+
+/// Groups of Strava sport types, matching the filter buttons on the map
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Category {
+    Ride,
+    Run,
+    Walk,
+    Swim,
+    Water,
+    Other,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_category() {
+        assert_eq!(SportType::GravelRide.category(), Category::Ride);
+        assert_eq!(SportType::Hike.category(), Category::Walk);
+        assert_eq!(SportType::Kayaking.category(), Category::Water);
+        assert_eq!(SportType::Yoga.category(), Category::Other);
+        // The water button has never covered these
+        assert_eq!(SportType::Windsurf.category(), Category::Other);
+        assert_eq!(serde_json::to_string(&Category::Run).unwrap(), r#""run""#);
+    }
+
+    #[test]
+    fn test_sport_type_serde() {
+        let got: Vec<SportType> = serde_json::from_str(r#"["TrailRun", "Hoverboard"]"#).unwrap();
+        let want = vec![
+            SportType::TrailRun,
+            SportType::Unknown("Hoverboard".to_string()),
+        ];
+        assert_eq!(got, want);
+        assert_eq!(got[1].category(), Category::Other);
+        let back = serde_json::to_string(&got).unwrap();
+        assert_eq!(back, r#"["TrailRun","Hoverboard"]"#);
+    }
+}
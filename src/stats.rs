@@ -0,0 +1,318 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Datelike, Duration, Months, NaiveDate, Utc};
+use rocket::FromFormField;
+use serde::Serialize;
+
+use crate::h3::{self, Attribution};
+use crate::models::Activity;
+use crate::privacy::{self, Zone};
+use crate::sport::Category;
+
+/// New cells found in a week or month, keyed by the first day of it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Period {
+    pub start: NaiveDate,
+    pub new_cells: usize,
+    pub activities: usize,
+}
+
+/// The activity that found the most new cells
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Best {
+    pub id: i64,
+    pub name: String,
+    pub start_date: DateTime<Utc>,
+    pub new_cells: usize,
+}
+
+/// A run of consecutive weeks that each had at least one new cell
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Streak {
+    pub weeks: usize,
+    pub start: Option<NaiveDate>,
+    pub end: Option<NaiveDate>,
+}
+
+/// Coverage statistics for the `/stats` endpoint
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub total_cells: usize,
+    pub sport_cells: BTreeMap<Category, usize>,
+    pub weekly: Vec<Period>,
+    pub monthly: Vec<Period>,
+    pub best: Option<Best>,
+    pub longest_streak: Streak,
+}
+
+/// Length of the periods to group activities into
+#[derive(Debug, Clone, Copy, PartialEq, FromFormField)]
+pub enum Interval {
+    #[field(value = "week")]
+    Week,
+    #[field(value = "month")]
+    Month,
+    #[field(value = "year")]
+    Year,
+}
+
+impl Interval {
+    /// First day of the period this date is in (weeks start on Monday)
+    pub fn start(&self, date: DateTime<Utc>) -> NaiveDate {
+        let date = date.date_naive();
+        match self {
+            Interval::Week => date - Duration::days(date.weekday().num_days_from_monday().into()),
+            Interval::Month => date.with_day(1).unwrap(),
+            Interval::Year => date.with_ordinal(1).unwrap(),
+        }
+    }
+
+    /// First day of the period after the one starting on `start`
+    pub fn next(&self, start: NaiveDate) -> NaiveDate {
+        match self {
+            Interval::Week => start + Duration::weeks(1),
+            Interval::Month => start + Months::new(1),
+            Interval::Year => start + Months::new(12),
+        }
+    }
+}
+
+/// Cells first visited during one interval of the timeline
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Step {
+    pub start: NaiveDate,
+    pub cells: Vec<String>,
+}
+
+/// Total up the attributions by period, oldest first
+/// Periods without any activities are left out
+fn group_by(attributions: &[Attribution], interval: Interval) -> Vec<Period> {
+    let mut periods: BTreeMap<NaiveDate, Period> = BTreeMap::new();
+    for attribution in attributions {
+        let start = interval.start(attribution.start_date);
+        let period = periods.entry(start).or_insert(Period {
+            start,
+            new_cells: 0,
+            activities: 0,
+        });
+        period.new_cells += attribution.new_cells;
+        period.activities += 1;
+    }
+    periods.into_values().collect()
+}
+
+/// Longest run of back-to-back weeks with new cells
+/// Expects the weekly periods from group_by, in order
+fn longest_streak(weekly: &[Period]) -> Streak {
+    let mut best = Streak::default();
+    let mut current = Streak::default();
+    for period in weekly.iter().filter(|p| p.new_cells > 0) {
+        let follows = current
+            .end
+            .is_some_and(|end| Interval::Week.next(end) == period.start);
+        if follows {
+            current.weeks += 1;
+        } else {
+            current = Streak {
+                weeks: 1,
+                start: Some(period.start),
+                end: None,
+            };
+        }
+        current.end = Some(period.start);
+        if current.weeks > best.weeks {
+            best = current.clone();
+        }
+    }
+    best
+}
+
+impl Stats {
+    /// Cells in privacy zones that shouldn't count are left out of everything
+    pub fn new(activities: &[Activity], zones: &[Zone]) -> Self {
+        let mut sport_cells = h3::polyfill_by_category(activities);
+        for cells in sport_cells.values_mut() {
+            *cells = privacy::mask_cells(std::mem::take(cells), zones);
+        }
+        let total_cells = h3::union(sport_cells.values()).len();
+        let sport_cells = sport_cells
+            .into_iter()
+            .map(|(category, cells)| (category, cells.len()))
+            .collect();
+
+        let attributions = h3::attribute(activities, zones);
+        let weekly = group_by(&attributions, Interval::Week);
+        let monthly = group_by(&attributions, Interval::Month);
+        let longest_streak = longest_streak(&weekly);
+
+        // On a tie, the earlier activity gets the credit
+        let best = attributions
+            .iter()
+            .filter(|a| a.new_cells > 0)
+            .rev()
+            .max_by_key(|a| a.new_cells)
+            .and_then(|best| {
+                let activity = activities.iter().find(|a| a.id == best.id)?;
+                Some(Best {
+                    id: best.id,
+                    name: activity.name.clone(),
+                    start_date: best.start_date,
+                    new_cells: best.new_cells,
+                })
+            });
+
+        Stats {
+            total_cells,
+            sport_cells,
+            weekly,
+            monthly,
+            best,
+            longest_streak,
+        }
+    }
+}
+
+/// How coverage grew, as the cells first visited in each interval
+/// Every interval from the first activity to the last is included,
+/// even if it has no new cells, so that the steps are evenly spaced in time
+/// Cells in privacy zones that shouldn't count are left out
+pub fn timeline(activities: &[Activity], interval: Interval, zones: &[Zone]) -> Vec<Step> {
+    let attributions = h3::attribute(activities, zones);
+    let (Some(first), Some(last)) = (attributions.first(), attributions.last()) else {
+        return vec![];
+    };
+    let end = interval.start(last.start_date);
+    let mut steps: Vec<Step> = Vec::new();
+    let mut start = interval.start(first.start_date);
+    while start <= end {
+        steps.push(Step {
+            start,
+            cells: vec![],
+        });
+        start = interval.next(start);
+    }
+    // Both are in date order, so walk through them together
+    let mut i = 0;
+    for attribution in attributions {
+        let start = interval.start(attribution.start_date);
+        while steps[i].start < start {
+            i += 1;
+        }
+        let cells = attribution.first_visited.iter().map(|c| format!("{:x}", c));
+        steps[i].cells.extend(cells);
+    }
+    steps
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::geo::fixture_activities;
+
+    fn week(y: i32, m: u32, d: u32, new_cells: usize) -> Period {
+        Period {
+            start: NaiveDate::from_ymd_opt(y, m, d).unwrap(),
+            new_cells,
+            activities: 1,
+        }
+    }
+
+    #[test]
+    fn test_interval() {
+        // A Sunday
+        let date = Utc.with_ymd_and_hms(2024, 1, 14, 18, 0, 0).unwrap();
+        assert_eq!(
+            Interval::Week.start(date),
+            NaiveDate::from_ymd_opt(2024, 1, 8).unwrap()
+        );
+        assert_eq!(
+            Interval::Month.start(date),
+            NaiveDate::from_ymd_opt(2024, 1, 1).unwrap()
+        );
+        assert_eq!(
+            Interval::Year.start(date),
+            NaiveDate::from_ymd_opt(2024, 1, 1).unwrap()
+        );
+
+        let start = NaiveDate::from_ymd_opt(2024, 1, 31).unwrap();
+        assert_eq!(
+            Interval::Month.next(start),
+            NaiveDate::from_ymd_opt(2024, 2, 29).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_longest_streak() {
+        let weekly = vec![
+            week(2024, 1, 1, 5),
+            week(2024, 1, 8, 3),
+            week(2024, 1, 15, 0),
+            week(2024, 1, 22, 1),
+            week(2024, 1, 29, 2),
+            week(2024, 2, 5, 8),
+            week(2024, 3, 4, 8),
+        ];
+        let streak = longest_streak(&weekly);
+        assert_eq!(streak.weeks, 3);
+        assert_eq!(streak.start, NaiveDate::from_ymd_opt(2024, 1, 22));
+        assert_eq!(streak.end, NaiveDate::from_ymd_opt(2024, 2, 5));
+
+        assert_eq!(longest_streak(&[]), Streak::default());
+    }
+
+    #[test]
+    fn test_stats() {
+        let activities = fixture_activities();
+        let stats = Stats::new(&activities, &[]);
+        assert_eq!(stats.total_cells, h3::polyfill_all(&activities).len());
+        assert!(stats.sport_cells[&Category::Run] > 0);
+
+        let weekly: usize = stats.weekly.iter().map(|p| p.new_cells).sum();
+        let monthly: usize = stats.monthly.iter().map(|p| p.new_cells).sum();
+        assert_eq!(weekly, stats.total_cells);
+        assert_eq!(monthly, stats.total_cells);
+        assert!(stats.weekly.len() > stats.monthly.len());
+
+        let best = stats.best.unwrap();
+        let best_week = stats
+            .weekly
+            .iter()
+            .find(|p| p.start == Interval::Week.start(best.start_date))
+            .unwrap();
+        assert!(best_week.new_cells >= best.new_cells);
+        assert!(stats.longest_streak.weeks >= 1);
+
+        // Cells around London that don't count are left out everywhere
+        let zones = [Zone {
+            center: geo::Point::new(-0.1276, 51.5072),
+            radius: 10_000.0,
+            count_cells: false,
+        }];
+        let masked = Stats::new(&activities, &zones);
+        assert!(masked.total_cells < stats.total_cells);
+        let weekly: usize = masked.weekly.iter().map(|p| p.new_cells).sum();
+        assert_eq!(weekly, masked.total_cells);
+        let steps = timeline(&activities, Interval::Month, &zones);
+        let cells: usize = steps.iter().map(|s| s.cells.len()).sum();
+        assert_eq!(cells, masked.total_cells);
+    }
+
+    #[test]
+    fn test_timeline() {
+        let activities = fixture_activities();
+        let steps = timeline(&activities, Interval::Month, &[]);
+        assert!(steps
+            .windows(2)
+            .all(|w| Interval::Month.next(w[0].start) == w[1].start));
+        let cells: usize = steps.iter().map(|s| s.cells.len()).sum();
+        assert_eq!(cells, h3::polyfill_all(&activities).len());
+
+        // The fixtures have a gap, so some weeks are empty
+        let steps = timeline(&activities, Interval::Week, &[]);
+        assert!(steps.iter().any(|s| s.cells.is_empty()));
+
+        assert!(timeline(&[], Interval::Week, &[]).is_empty());
+    }
+}
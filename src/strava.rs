@@ -5,6 +5,10 @@ use std::env;
 use url::{ParseError, Url};
 
 use crate::error::Error;
+use crate::sport::SportType;
+
+/// The most activities Strava will return in one request
+pub const PER_PAGE: usize = 200;
 
 pub enum GrantType {
     Auth,
@@ -24,6 +28,25 @@ pub struct TokenResponse {
     pub expires_at: i32,
 }
 
+#[derive(Deserialize)]
+pub struct Totals {
+    pub count: u32,
+}
+
+/// Only rides, runs and swims are counted, so this undercounts other sports
+#[derive(Deserialize)]
+pub struct AthleteStats {
+    pub all_ride_totals: Totals,
+    pub all_run_totals: Totals,
+    pub all_swim_totals: Totals,
+}
+
+impl AthleteStats {
+    pub fn count(&self) -> u32 {
+        self.all_ride_totals.count + self.all_run_totals.count + self.all_swim_totals.count
+    }
+}
+
 #[derive(Deserialize)]
 pub struct Map {
     pub summary_polyline: Option<String>,
@@ -39,7 +62,7 @@ pub struct ActivityResponse {
     pub start_date: chrono::DateTime<chrono::Utc>,
     pub kudos_count: i32,
     pub average_speed: f64,
-    pub sport_type: String,
+    pub sport_type: SportType,
     pub map: Map,
 }
 
@@ -71,11 +94,17 @@ impl StravaClient {
         }
     }
 
-    fn create_activities_url(&self) -> Result<String, ParseError> {
+    fn create_activities_url(&self, after: Option<i64>, page: u32) -> Result<String, ParseError> {
         let mut url = self.base.clone();
         let path = "api/v3/athlete/activities";
         url = url.join(path)?;
-        url.query_pairs_mut().append_pair("per_page", "200");
+        url.query_pairs_mut()
+            .append_pair("per_page", &PER_PAGE.to_string())
+            .append_pair("page", &page.to_string());
+        if let Some(after) = after {
+            url.query_pairs_mut()
+                .append_pair("after", &after.to_string());
+        }
         Ok(url.to_string())
     }
 
@@ -109,8 +138,46 @@ impl StravaClient {
         Ok(url.to_string())
     }
 
+    fn create_deauthorize_url(&self, token: &str) -> Result<String, ParseError> {
+        let mut url = self.base.clone();
+        let path = "/oauth/deauthorize";
+        url = url.join(path)?;
+        url.query_pairs_mut().append_pair("access_token", token);
+        Ok(url.to_string())
+    }
+
     pub async fn get_activities(&self, token: &str) -> Result<Vec<ActivityResponse>, Error> {
-        let url = self.create_activities_url()?;
+        self.fetch_activities(token, None, 1).await
+    }
+
+    /// One page (starting at 1) of `PER_PAGE` activities, newest first
+    /// An empty page means there are no more
+    pub async fn get_activities_page(
+        &self,
+        token: &str,
+        page: u32,
+    ) -> Result<Vec<ActivityResponse>, Error> {
+        self.fetch_activities(token, None, page).await
+    }
+
+    /// One page (starting at 1) of the activities that started after `after`
+    /// (a Unix timestamp), oldest first
+    pub async fn get_activities_after(
+        &self,
+        token: &str,
+        after: i64,
+        page: u32,
+    ) -> Result<Vec<ActivityResponse>, Error> {
+        self.fetch_activities(token, Some(after), page).await
+    }
+
+    async fn fetch_activities(
+        &self,
+        token: &str,
+        after: Option<i64>,
+        page: u32,
+    ) -> Result<Vec<ActivityResponse>, Error> {
+        let url = self.create_activities_url(after, page)?;
         let client = reqwest::Client::new();
         let bearer = format!("Bearer {}", token);
         let response = client
@@ -126,6 +193,29 @@ impl StravaClient {
         Ok(body)
     }
 
+    pub async fn get_athlete_stats(
+        &self,
+        token: &str,
+        athlete_id: i32,
+    ) -> Result<AthleteStats, Error> {
+        let url = self
+            .base
+            .join(&format!("api/v3/athletes/{}/stats", athlete_id))?;
+        let client = reqwest::Client::new();
+        let bearer = format!("Bearer {}", token);
+        let response = client
+            .get(url)
+            .header(AUTHORIZATION, bearer)
+            .send()
+            .await?
+            .error_for_status()?;
+        let body = response
+            .json::<AthleteStats>()
+            .await
+            .with_context(|| "strava::get_athlete_stats".to_string())?;
+        Ok(body)
+    }
+
     pub async fn get_token(
         &self,
         code: &str,
@@ -141,6 +231,14 @@ impl StravaClient {
             .with_context(|| "strava::get_token".to_string())?;
         Ok(body)
     }
+
+    /// Revoke hexy's access to the athlete, invalidating all their tokens
+    pub async fn deauthorize(&self, token: &str) -> Result<(), Error> {
+        let url = self.create_deauthorize_url(token)?;
+        let client = reqwest::Client::new();
+        client.post(url).send().await?.error_for_status()?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -163,6 +261,89 @@ mod tests {
         let res = sc.get_activities("").await.unwrap();
 
         mock.assert();
-        assert!(res.len() == 0);
+        assert!(res.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_get_activities_after() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(GET)
+                .path("/api/v3/athlete/activities")
+                .query_param("after", "1711929600")
+                .query_param("page", "2");
+            then.status(200).body(r#"[]"#);
+        });
+
+        let sc = StravaClient::new(&server.url("/"), "", "", "");
+        let res = sc.get_activities_after("", 1711929600, 2).await.unwrap();
+
+        mock.assert();
+        assert!(res.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_get_activities_page() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(GET)
+                .path("/api/v3/athlete/activities")
+                .query_param("per_page", "200")
+                .query_param("page", "3");
+            then.status(200).body(r#"[]"#);
+        });
+
+        let sc = StravaClient::new(&server.url("/"), "", "", "");
+        assert!(sc.get_activities_page("", 3).await.unwrap().is_empty());
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_get_athlete_stats() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(GET).path("/api/v3/athletes/42/stats");
+            then.status(200).body(
+                r#"{"all_ride_totals": {"count": 10, "distance": 1.0},
+                    "all_run_totals": {"count": 20, "distance": 1.0},
+                    "all_swim_totals": {"count": 3, "distance": 1.0}}"#,
+            );
+        });
+
+        let sc = StravaClient::new(&server.url("/"), "", "", "");
+        let stats = sc.get_athlete_stats("", 42).await.unwrap();
+        mock.assert();
+        assert_eq!(stats.count(), 33);
+    }
+
+    #[tokio::test]
+    async fn test_error_redacted() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(POST).path("/oauth/token");
+            then.status(500);
+        });
+
+        let sc = StravaClient::new(&server.url("/"), "id", "hunter2", "");
+        let err = sc.get_token("code", GrantType::Refresh).await.unwrap_err();
+        assert!(err.0.to_string().contains("hunter2"));
+        assert!(!err.redacted().contains("hunter2"));
+        assert!(err.redacted().contains("500"));
+    }
+
+    #[tokio::test]
+    async fn test_deauthorize() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/oauth/deauthorize")
+                .query_param("access_token", "abc");
+            then.status(200).body(r#"{"access_token":"abc"}"#);
+        });
+
+        let sc = StravaClient::new(&server.url("/"), "", "", "");
+        sc.deauthorize("abc").await.unwrap();
+        mock.assert();
+        assert!(sc.deauthorize("wrong").await.is_err());
     }
 }
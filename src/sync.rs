@@ -0,0 +1,378 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use chrono::Utc;
+use log::{info, warn};
+use rocket::fairing::AdHoc;
+use rocket::tokio::time::Instant;
+
+use crate::db::{self, Db};
+use crate::error;
+use crate::models::{is_dt_past, ts_to_dt, NewSyncJobDb, UserDb};
+use crate::strava;
+
+/// Strava allows 100 requests every 15 minutes (per app, not per user)
+/// so by default the scheduler and job worker take half and leave the rest for people using the site
+const DEFAULT_BUDGET: usize = 50;
+const DEFAULT_INTERVAL_MINS: u64 = 15;
+
+/// Only users with an activity in this many days get synced in the background
+const ACTIVE_DAYS: i64 = 30;
+
+/// How often the scheduler runs, and how many Strava requests it and the job worker
+/// can make between them in that time
+/// Set with the `SYNC_INTERVAL_MINS` and `SYNC_BUDGET` env vars
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Config {
+    pub interval: Duration,
+    pub budget: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let mins = env::var("SYNC_INTERVAL_MINS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_INTERVAL_MINS);
+        let budget = env::var("SYNC_BUDGET")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_BUDGET);
+        Config {
+            interval: Duration::from_secs(60 * mins),
+            budget,
+        }
+    }
+}
+
+/// Strava requests left for background work in the current interval
+/// Shared by the scheduler and the job worker, so together they stay within `Config::budget`
+#[derive(Debug, Clone)]
+pub struct Budget {
+    config: Config,
+    /// When the current interval started, and how much of it has been spent
+    spent: Arc<Mutex<(Instant, usize)>>,
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Budget::new(Config::default())
+    }
+}
+
+impl Budget {
+    pub fn new(config: Config) -> Self {
+        Budget {
+            config,
+            spent: Arc::new(Mutex::new((Instant::now(), 0))),
+        }
+    }
+
+    pub fn config(&self) -> Config {
+        self.config
+    }
+
+    /// What's been spent so far, starting again once the interval is up
+    fn spent(&self) -> MutexGuard<'_, (Instant, usize)> {
+        let mut spent = self.spent.lock().unwrap();
+        let now = Instant::now();
+        if now >= spent.0 + self.config.interval {
+            *spent = (now, 0);
+        }
+        spent
+    }
+
+    /// Spend `n` requests if there are that many left, otherwise how long until there are more
+    fn take(&self, n: usize) -> Result<(), Duration> {
+        let mut spent = self.spent();
+        if spent.1 + n <= self.config.budget {
+            spent.1 += n;
+            Ok(())
+        } else {
+            Err((spent.0 + self.config.interval).saturating_duration_since(Instant::now()))
+        }
+    }
+
+    /// How many requests are left in this interval
+    pub fn remaining(&self) -> usize {
+        self.config.budget.saturating_sub(self.spent().1)
+    }
+
+    /// Spend `n` requests if there are that many left, without waiting
+    pub fn try_spend(&self, n: usize) -> bool {
+        self.take(n).is_ok()
+    }
+
+    /// Spend one request, waiting for the next interval if there are none left
+    pub async fn spend(&self) {
+        while let Err(wait) = self.take(1) {
+            rocket::tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Whether this user's access token has expired or will within the hour
+fn needs_refresh(user: &UserDb) -> bool {
+    is_dt_past(ts_to_dt(user.expires_at))
+}
+
+/// Swap the user's refresh token for a new access token and store both
+async fn refresh(conn: &Db, user: &UserDb) -> Result<String, error::Error> {
+    info!("getting new token for id {}", user.id);
+    let token_response = strava::StravaClient::default()
+        .get_token(&user.refresh_token, strava::GrantType::Refresh)
+        .await?;
+    db::save_user(conn, &token_response).await?;
+    Ok(token_response.access_token)
+}
+
+/// Get a valid access token for this user, refreshing it if needed
+pub async fn get_token(conn: &Db, id: i32) -> Result<String, error::Error> {
+    let user = db::get_user(conn, id).await?;
+    // previously I was just getting a token out of the cookie
+    // which was quite elegant, but didn't provide for refreshing...
+    if needs_refresh(&user) {
+        refresh(conn, &user).await
+    } else {
+        Ok(user.access_token)
+    }
+}
+
+/// Fetch and store anything newer than the user's latest stored activity
+/// a page at a time, so that nothing is missed after a long gap
+/// With nothing stored yet it's just the latest page, the rest is left to jobs
+/// Each page is a request from the `budget`, if there is one, and it stops early
+/// once that runs out, carrying on from the latest stored activity next time
+/// Returns how many activities were fetched
+pub async fn sync(
+    conn: &Db,
+    client: &strava::StravaClient,
+    budget: Option<&Budget>,
+    id: i32,
+    token: &str,
+) -> Result<usize, error::Error> {
+    let can_fetch = || budget.is_none_or(|b| b.try_spend(1));
+    let Some(after) = db::get_latest_start_date(conn, id).await? else {
+        if !can_fetch() {
+            return Ok(0);
+        }
+        let activities = client.get_activities(token).await?;
+        if !activities.is_empty() {
+            db::save_activities(conn, id, &activities).await?;
+            db::update_cells(conn, id).await?;
+        }
+        return Ok(activities.len());
+    };
+    // Strava sends activities after a date oldest first, so stopping early leaves no gaps
+    let mut fetched = 0;
+    for page in 1.. {
+        if !can_fetch() {
+            break;
+        }
+        let activities = client.get_activities_after(token, after, page).await?;
+        if !activities.is_empty() {
+            db::save_activities(conn, id, &activities).await?;
+        }
+        fetched += activities.len();
+        if activities.len() < strava::PER_PAGE {
+            break;
+        }
+    }
+    if fetched > 0 {
+        db::update_cells(conn, id).await?;
+    }
+    Ok(fetched)
+}
+
+/// Requests needed before syncing, ie one if the token needs refreshing first
+/// The sync itself spends a request per page as it goes
+fn cost(user: &UserDb) -> usize {
+    if needs_refresh(user) {
+        1
+    } else {
+        0
+    }
+}
+
+/// Pick who to sync this time, least recently synced first, within the budget
+/// assuming each sync is a single page, as it usually is
+fn plan(mut users: Vec<UserDb>, last_synced: &HashMap<i32, i64>, budget: usize) -> Vec<UserDb> {
+    users.sort_by_key(|u| (last_synced.get(&u.id).copied(), u.id));
+    let mut spent = 0;
+    users
+        .into_iter()
+        .take_while(|u| {
+            spent += cost(u) + 1;
+            spent <= budget
+        })
+        .collect()
+}
+
+/// Record how a job went in the sync_jobs table
+async fn record(
+    conn: &Db,
+    user_id: i32,
+    kind: &str,
+    started_at: i64,
+    result: Result<usize, &error::Error>,
+) -> Result<usize, error::Error> {
+    let (status, activities, error) = match result {
+        Ok(n) => ("ok", n as i32, None),
+        Err(e) => ("failed", 0, Some(e.redacted())),
+    };
+    let job = NewSyncJobDb {
+        user_id,
+        kind: kind.to_string(),
+        status: status.to_string(),
+        started_at,
+        finished_at: Utc::now().timestamp(),
+        activities,
+        error,
+    };
+    db::save_sync_job(conn, job).await
+}
+
+/// One run of the scheduler: refresh tokens that are about to expire
+/// and sync as many active users as whatever's left of the budget allows
+/// Returns how many users were synced
+pub async fn run_once(conn: &Db, budget: &Budget) -> Result<usize, error::Error> {
+    let since = Utc::now().timestamp() - ACTIVE_DAYS * 24 * 60 * 60;
+    let users = db::get_active_users(conn, since).await?;
+    let last_synced = db::get_last_synced(conn).await?;
+    let users = plan(users, &last_synced, budget.remaining());
+    let mut count = 0;
+    for user in users {
+        // The job worker, or a sync of more than a page, might have spent some since planning
+        if budget.remaining() == 0 || !budget.try_spend(cost(&user)) {
+            break;
+        }
+        count += 1;
+        let token = if needs_refresh(&user) {
+            let started_at = Utc::now().timestamp();
+            let token = refresh(conn, &user).await;
+            record(
+                conn,
+                user.id,
+                "refresh",
+                started_at,
+                token.as_ref().map(|_| 0),
+            )
+            .await?;
+            match token {
+                Ok(token) => token,
+                Err(e) => {
+                    warn!("background refresh failed for user {}: {}", user.id, e.0);
+                    continue;
+                }
+            }
+        } else {
+            user.access_token.clone()
+        };
+        let started_at = Utc::now().timestamp();
+        let result = sync(
+            conn,
+            &strava::StravaClient::default(),
+            Some(budget),
+            user.id,
+            &token,
+        )
+        .await;
+        if let Err(e) = &result {
+            warn!("background sync failed for user {}: {}", user.id, e.0);
+        }
+        record(conn, user.id, "sync", started_at, result.as_ref().copied()).await?;
+    }
+    Ok(count)
+}
+
+/// Runs the scheduler every `Config::interval` in a background task
+/// The first run is one interval after launch, so restarts don't burn the budget
+pub fn fairing() -> AdHoc {
+    AdHoc::on_liftoff("Scheduler", |rocket| {
+        Box::pin(async move {
+            let Some(conn) = Db::get_one(rocket).await else {
+                warn!("no db connection for the scheduler");
+                return;
+            };
+            let budget = rocket.state::<Budget>().cloned().unwrap_or_default();
+            let config = budget.config();
+            info!("scheduling syncs every {:?}", config.interval);
+            rocket::tokio::spawn(async move {
+                let start = rocket::tokio::time::Instant::now() + config.interval;
+                let mut interval = rocket::tokio::time::interval_at(start, config.interval);
+                loop {
+                    interval.tick().await;
+                    match run_once(&conn, &budget).await {
+                        Ok(count) => info!("scheduler synced {} users", count),
+                        Err(e) => warn!("scheduler failed: {}", e.0),
+                    }
+                }
+            });
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: i32, expires_in: i64) -> UserDb {
+        UserDb {
+            id,
+            access_token: String::new(),
+            refresh_token: String::new(),
+            expires_at: (Utc::now().timestamp() + expires_in) as i32,
+        }
+    }
+
+    #[test]
+    fn test_plan() {
+        let hours = 60 * 60;
+        let users = vec![
+            user(1, 5 * hours),
+            user(2, 5 * hours),
+            user(3, 0),
+            user(4, 5 * hours),
+        ];
+        // 4 has never been synced, 3 longest ago
+        let last_synced = HashMap::from([(1, 300), (2, 200), (3, 100)]);
+
+        // Only the refresh is up front, the pages are spent as they're fetched
+        assert_eq!(cost(&users[2]), 1);
+        assert_eq!(cost(&users[0]), 0);
+
+        let ids = |users: Vec<UserDb>| users.iter().map(|u| u.id).collect::<Vec<_>>();
+        assert_eq!(ids(plan(users.clone(), &last_synced, 10)), vec![4, 3, 2, 1]);
+        // 3 needs a refresh before its first page, so takes two
+        assert_eq!(ids(plan(users.clone(), &last_synced, 3)), vec![4, 3]);
+        assert_eq!(ids(plan(users.clone(), &last_synced, 2)), vec![4]);
+        assert!(plan(users, &last_synced, 0).is_empty());
+    }
+
+    #[test]
+    fn test_budget() {
+        let hour = Config {
+            interval: Duration::from_secs(60 * 60),
+            budget: 3,
+        };
+        let budget = Budget::new(hour);
+        // The scheduler and the job worker each get a clone
+        let worker = budget.clone();
+        assert!(budget.try_spend(2));
+        assert_eq!(worker.remaining(), 1);
+        assert!(!worker.try_spend(2));
+        assert!(worker.try_spend(1));
+        assert_eq!(budget.remaining(), 0);
+        assert!(budget.take(1).unwrap_err() <= hour.interval);
+
+        // Everything's back once the interval is up
+        let budget = Budget::new(Config {
+            interval: Duration::ZERO,
+            budget: 3,
+        });
+        assert!(budget.try_spend(3));
+        assert_eq!(budget.remaining(), 3);
+    }
+}
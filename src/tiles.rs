@@ -0,0 +1,288 @@
+use std::f64::consts::PI;
+
+use geo::{
+    BooleanOps, BoundingRect, Coord, Intersects, LineString, MapCoords, MultiLineString, Rect,
+    Simplify,
+};
+use geojson::JsonValue;
+use h3o::{CellIndex, LatLng};
+use mvt::{GeomEncoder, GeomType, Layer, Tile};
+
+use crate::error;
+use crate::models::Activity;
+use crate::privacy::{self, Zone};
+
+/// Width/height of a tile in tile units
+pub const EXTENT: u32 = 4096;
+
+/// How far (in tile units) geometries are kept past the tile edge
+/// so that lines and fills join up across neighbouring tiles
+const BUFFER: f64 = 64.0;
+
+/// Simplification tolerance in tile units (roughly half a pixel)
+/// As this is in tile space, lines get simplified more at lower zooms
+const TOLERANCE: f64 = 8.0;
+
+/// Deepest zoom we'll serve tiles for
+const MAX_ZOOM: u32 = 22;
+
+/// Margin in degrees (a bit more than a res 9 cell) around the tile within
+/// which a cell's centre can be for the cell to still overlap the tile
+const CELL_MARGIN: f64 = 0.01;
+
+/// A Web Mercator (XYZ) tile address
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TileId {
+    pub z: u32,
+    pub x: u32,
+    pub y: u32,
+}
+
+impl TileId {
+    /// Returns None if the tile doesn't exist at this zoom
+    pub fn new(z: u32, x: u32, y: u32) -> Option<TileId> {
+        if z > MAX_ZOOM || x >= 1 << z || y >= 1 << z {
+            return None;
+        }
+        Some(TileId { z, x, y })
+    }
+
+    fn size(&self) -> f64 {
+        (1u32 << self.z) as f64
+    }
+
+    /// Convert a lon/lat coord to this tile's coordinate space
+    /// where (0, 0) is the top-left and (EXTENT, EXTENT) the bottom-right
+    pub fn project(&self, c: Coord) -> Coord {
+        let n = self.size();
+        let lat = c.y.to_radians();
+        let x = (c.x + 180.0) / 360.0 * n - self.x as f64;
+        let y = (1.0 - lat.tan().asinh() / PI) / 2.0 * n - self.y as f64;
+        Coord {
+            x: x * EXTENT as f64,
+            y: y * EXTENT as f64,
+        }
+    }
+
+    /// Convert a coord in this tile's coordinate space back to lon/lat
+    fn unproject(&self, c: Coord) -> Coord {
+        let n = self.size();
+        let x = (c.x / EXTENT as f64 + self.x as f64) / n;
+        let y = (c.y / EXTENT as f64 + self.y as f64) / n;
+        Coord {
+            x: x * 360.0 - 180.0,
+            y: (PI * (1.0 - 2.0 * y)).sinh().atan().to_degrees(),
+        }
+    }
+
+    /// The bounds plus `CELL_MARGIN`, where the centre of any cell overlapping the tile is
+    pub fn near(&self) -> Rect {
+        let bounds = self.bounds();
+        let margin = Coord {
+            x: CELL_MARGIN,
+            y: CELL_MARGIN,
+        };
+        Rect::new(bounds.min() - margin, bounds.max() + margin)
+    }
+
+    /// The lon/lat bounds of this tile, including the buffer
+    pub fn bounds(&self) -> Rect {
+        Rect::new(
+            self.unproject(Coord {
+                x: -BUFFER,
+                y: -BUFFER,
+            }),
+            self.unproject(Coord {
+                x: EXTENT as f64 + BUFFER,
+                y: EXTENT as f64 + BUFFER,
+            }),
+        )
+    }
+}
+
+/// Add the JSON properties of an activity as MVT tags
+fn add_tags(feature: &mut mvt::Feature, activity: &Activity) {
+    let Some(properties) = activity.to_properties() else {
+        return;
+    };
+    for (key, value) in properties {
+        match value {
+            JsonValue::String(s) => feature.add_tag_string(&key, &s),
+            JsonValue::Bool(b) => feature.add_tag_bool(&key, b),
+            JsonValue::Number(n) => match n.as_i64() {
+                Some(i) => feature.add_tag_sint(&key, i),
+                None => feature.add_tag_double(&key, n.as_f64().unwrap_or_default()),
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Build the layer of activity lines, clipped and simplified to the tile
+fn activities_layer(
+    tile: &Tile,
+    id: &TileId,
+    activities: &[Activity],
+) -> Result<Layer, error::Error> {
+    let mut layer = tile.create_layer("activities");
+    let bounds = id.bounds();
+    let clip = Rect::new(
+        Coord {
+            x: -BUFFER,
+            y: -BUFFER,
+        },
+        Coord {
+            x: EXTENT as f64 + BUFFER,
+            y: EXTENT as f64 + BUFFER,
+        },
+    )
+    .to_polygon();
+    for activity in activities {
+        let Some(ls) = &activity.linestring else {
+            continue;
+        };
+        if !ls.bounding_rect().is_some_and(|r| r.intersects(&bounds)) {
+            continue;
+        }
+        let projected: LineString = ls.map_coords(|c| id.project(c));
+        let clipped = clip.clip(&MultiLineString::new(vec![projected]), false);
+        let simplified = clipped.simplify(&TOLERANCE);
+
+        let mut encoder = GeomEncoder::new(GeomType::Linestring);
+        for part in simplified.iter().filter(|part| part.0.len() > 1) {
+            for c in part.coords() {
+                encoder.add_point(c.x, c.y)?;
+            }
+            encoder.complete_geom()?;
+        }
+        let geom = encoder.encode()?;
+        if geom.is_empty() {
+            continue;
+        }
+        let mut feature = layer.into_feature(geom);
+        feature.set_id(activity.id as u64);
+        add_tags(&mut feature, activity);
+        layer = feature.into_layer();
+    }
+    Ok(layer)
+}
+
+/// Build the layer of visited cells that overlap the tile
+fn cells_layer(tile: &Tile, id: &TileId, cells: &[CellIndex]) -> Result<Layer, error::Error> {
+    let mut layer = tile.create_layer("cells");
+    let bounds = id.bounds();
+    // Checking the centre first is much cheaper than working out every boundary
+    let near = id.near();
+    for cell in cells {
+        if !near.intersects(&Coord::from(LatLng::from(*cell))) {
+            continue;
+        }
+        let ring = LineString::from(cell.boundary());
+        if !ring.bounding_rect().is_some_and(|r| r.intersects(&bounds)) {
+            continue;
+        }
+        let mut encoder = GeomEncoder::new(GeomType::Polygon);
+        for c in ring.coords() {
+            let c = id.project(*c);
+            encoder.add_point(c.x, c.y)?;
+        }
+        let mut feature = layer.into_feature(encoder.encode()?);
+        feature.set_id(u64::from(*cell));
+        layer = feature.into_layer();
+    }
+    Ok(layer)
+}
+
+/// Encode activities and their cells as a Mapbox Vector Tile
+/// with an `activities` layer of lines and a `cells` layer of polygons
+/// The cells are the stored ones, so already respect the privacy zones,
+/// and the lines get trimmed at them here
+pub fn encode(
+    id: &TileId,
+    activities: Vec<Activity>,
+    cells: &[CellIndex],
+    zones: &[Zone],
+) -> Result<Vec<u8>, error::Error> {
+    let activities = privacy::apply(activities, zones);
+
+    let mut tile = Tile::new(EXTENT);
+    let layer = activities_layer(&tile, id, &activities)?;
+    tile.add_layer(layer)?;
+    let layer = cells_layer(&tile, id, cells)?;
+    tile.add_layer(layer)?;
+    Ok(tile.to_bytes()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use geo::line_string;
+    use h3o::Resolution;
+
+    use crate::sport::SportType;
+
+    use super::*;
+
+    fn activity(linestring: LineString) -> Activity {
+        Activity {
+            id: 1,
+            name: "Morning Run".to_string(),
+            distance: 5000.0,
+            moving_time: 1500,
+            elapsed_time: 1600,
+            start_date: Utc::now(),
+            kudos_count: 3,
+            average_speed: 3.3,
+            sport_type: SportType::Run,
+            linestring: Some(linestring),
+        }
+    }
+
+    #[test]
+    fn test_tile_id() {
+        assert!(TileId::new(0, 0, 0).is_some());
+        assert!(TileId::new(2, 3, 3).is_some());
+        assert!(TileId::new(2, 4, 0).is_none());
+        assert!(TileId::new(23, 0, 0).is_none());
+    }
+
+    #[test]
+    fn test_project() {
+        let id = TileId::new(1, 1, 0).unwrap();
+        let got = id.project(Coord { x: 0.0, y: 0.0 });
+        assert!(got.x.abs() < 1e-6);
+        assert!((got.y - EXTENT as f64).abs() < 1e-6);
+
+        let c = Coord { x: 12.34, y: 56.78 };
+        let back = id.unproject(id.project(c));
+        assert!((back.x - c.x).abs() < 1e-9);
+        assert!((back.y - c.y).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_activities_layer() {
+        // z12 tile containing central London
+        let id = TileId::new(12, 2046, 1362).unwrap();
+        let inside = activity(line_string![(x: -0.1276, y: 51.5072), (x: -0.1301, y: 51.5104)]);
+        let outside = activity(line_string![(x: -3.1883, y: 55.9533), (x: -3.1712, y: 55.9445)]);
+
+        let tile = Tile::new(EXTENT);
+        let layer = activities_layer(&tile, &id, &[inside, outside]).unwrap();
+        assert_eq!(layer.num_features(), 1);
+    }
+
+    #[test]
+    fn test_cells_layer() {
+        let id = TileId::new(12, 2046, 1362).unwrap();
+        let london = LatLng::new(51.5072, -0.1276)
+            .unwrap()
+            .to_cell(Resolution::Nine);
+        let edinburgh = LatLng::new(55.9533, -3.1883)
+            .unwrap()
+            .to_cell(Resolution::Nine);
+
+        let tile = Tile::new(EXTENT);
+        let layer = cells_layer(&tile, &id, &[london, edinburgh]).unwrap();
+        assert_eq!(layer.num_features(), 1);
+    }
+}
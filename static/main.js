@@ -1,6 +1,8 @@
 import {
   transformRequest,
+  addActivities,
   fetchData,
+  watchSync,
   mapInteractions,
   setupFilters,
   setupInfoClick,
@@ -20,7 +22,7 @@ const map = new maplibregl.Map({
     [-10.7, 49.5],
     [1.9, 61.3],
   ],
-  interactive: logged_in,
+  interactive: logged_in || read_only,
 });
 map.dragRotate.disable();
 map.touchZoomRotate.disableRotation();
@@ -33,9 +35,15 @@ map.addControl(
 
 map.on("load", () => {
   if (logged_in) {
-    fetchData(map);
+    // Straight away, as the tiles don't wait for the data
+    // (shared maps only have the hexagons)
+    addActivities(map);
+    // After the data, as that's what queues a first sync
+    fetchData(map, data_url).then(() => watchSync(map, data_url));
     mapInteractions(map);
     setupFilters(map);
+  } else if (read_only) {
+    fetchData(map, data_url);
   }
 });
 
@@ -86,30 +86,40 @@ const activeFilters = {
   btnWater: false,
 };
 
+// The server works out which category each sport_type is in
 const filterMap = {
-  btnRide: [
-    "Ride",
-    "EBikeRide",
-    "EMountainBikeRide",
-    "GravelRide",
-    "MountainBikeRide",
-  ],
-  btnRun: ["Run", "TrailRun"],
-  btnWalk: ["Walk", "Hike"],
-  btnSwim: ["Swim"],
-  btnWater: ["Canoeing", "Kayaking", "Kitesurf", "Rowing", "Sail", "Surfing"],
+  btnRide: "ride",
+  btnRun: "run",
+  btnWalk: "walk",
+  btnSwim: "swim",
+  btnWater: "water",
 };
 
+// category -> cells, from the backend
+let sportCells = {};
+// all the cells on the map, including any new ones from /events
+let allCells = [];
+// activity id -> properties, from the backend
+let activityProps = new Map();
+
 const updateFilters = (map) => {
   let filters = ["all"];
+  const hidden = [];
   Object.keys(activeFilters).forEach((key) => {
     if (activeFilters[key]) {
-      filterMap[key].forEach((t) => {
-        filters.push(["!=", ["get", "sport_type"], t]);
-      });
+      filters.push(["!=", ["get", "category"], filterMap[key]]);
+      hidden.push(filterMap[key]);
     }
   });
   map.setFilter("activities", filters.length > 1 ? filters : null);
+
+  const cells = new Set();
+  Object.keys(sportCells).forEach((category) => {
+    if (!hidden.includes(category)) {
+      sportCells[category].forEach((c) => cells.add(c));
+    }
+  });
+  map.getSource("hex")?.setData(makeHexes([...cells]));
 };
 
 const toggleButtonState = (map, id) => {
@@ -128,23 +138,42 @@ export const setupFilters = (map) => {
   });
 };
 
-const processData = async (map, { activities, cells, centroid }) => {
-  map.addSource("hex", { type: "geojson", data: makeHexes(cells) });
-  map.addLayer({
-    id: "hex",
-    type: "fill",
-    source: "hex",
-    paint: {
-      "fill-color": "hsla(0, 50%, 50%, 0.3)",
-      "fill-outline-color": "rgba(0,0,0,0)",
-    },
+const setupRegions = (map, regions) => {
+  if (!regions || regions.length < 2) return;
+  const list = $("regions");
+  regions.forEach((region, i) => {
+    const button = document.createElement("div");
+    button.className = "cursor-pointer bg-white rounded shadow-md py-2 px-2";
+    button.innerText = `📍 ${i + 1}: ${region.count} activities`;
+    button.addEventListener("click", () => {
+      map.fitBounds(
+        [
+          [region.bbox.min.x, region.bbox.min.y],
+          [region.bbox.max.x, region.bbox.max.y],
+        ],
+        { padding: 40, maxZoom: 14 },
+      );
+    });
+    list.appendChild(button);
   });
+  list.style.display = "flex";
+};
 
-  map.addSource("activities", { type: "geojson", data: activities });
+// The lines come as vector tiles, so they show up a bit at a time
+// rather than all at once after the whole of /data
+const tileUrl = () => [`${window.location.origin}/tiles/{z}/{x}/{y}.mvt`];
+
+export const addActivities = (map) => {
+  map.addSource("activities", {
+    type: "vector",
+    tiles: tileUrl(),
+    maxzoom: 14,
+  });
   map.addLayer({
     id: "activities",
     type: "line",
     source: "activities",
+    "source-layer": "activities",
     layout: { "line-join": "round", "line-cap": "round" },
     paint: {
       "line-color": [
@@ -152,29 +181,18 @@ const processData = async (map, { activities, cells, centroid }) => {
         ["boolean", ["feature-state", "selected"], false],
         "#000000",
         [
-          "any",
-          ["==", ["get", "sport_type"], "EBikeRide"],
-          ["==", ["get", "sport_type"], "EMountainBikeRide"],
-          ["==", ["get", "sport_type"], "GravelRide"],
-          ["==", ["get", "sport_type"], "MountainBikeRide"],
-          ["==", ["get", "sport_type"], "Ride"],
+          "match",
+          ["get", "category"],
+          "ride",
+          "#984ea3", // lilac
+          "run",
+          "#ff7f00", // orange
+          "walk",
+          "#4daf4a", // green
+          "swim",
+          "#377eb8", // blue
+          "#595959", // dark grey
         ],
-        "#984ea3", // lilac
-        [
-          "any",
-          ["==", ["get", "sport_type"], "Run"],
-          ["==", ["get", "sport_type"], "TrailRun"],
-        ],
-        "#ff7f00", // orange
-        [
-          "any",
-          ["==", ["get", "sport_type"], "Walk"],
-          ["==", ["get", "sport_type"], "Hike"],
-        ],
-        "#4daf4a", // green
-        ["==", ["get", "sport_type"], "Swim"],
-        "#377eb8", // blue
-        "#595959", // dark grey
       ],
       "line-opacity": ["interpolate", ["linear"], ["zoom"], 7, 0.6, 15, 0.5],
       "line-width": [
@@ -188,18 +206,60 @@ const processData = async (map, { activities, cells, centroid }) => {
       ],
     },
   });
-  if (centroid && map.getZoom() < 9) {
+};
+
+const processData = async (
+  map,
+  { activities, cells, centroid, bbox, regions, sport_cells },
+) => {
+  sportCells = sport_cells || {};
+  allCells = cells;
+  // The tiles don't have everything shown when an activity is clicked
+  activityProps = new Map(
+    (activities?.features || []).map((f) => [f.properties.id, f.properties]),
+  );
+  // Already on the map, so this is a reload after a sync
+  if (map.getSource("hex")) {
+    map.getSource("hex").setData(makeHexes(cells));
+    map.getSource("activities")?.setTiles(tileUrl());
+    return;
+  }
+  map.addSource("hex", { type: "geojson", data: makeHexes(cells) });
+  // Under the lines, which were added before the data came
+  map.addLayer(
+    {
+      id: "hex",
+      type: "fill",
+      source: "hex",
+      paint: {
+        "fill-color": "hsla(0, 50%, 50%, 0.3)",
+        "fill-outline-color": "rgba(0,0,0,0)",
+      },
+    },
+    map.getLayer("activities") ? "activities" : undefined,
+  );
+
+  if (bbox && map.getZoom() < 9) {
+    map.fitBounds(
+      [
+        [bbox.min.x, bbox.min.y],
+        [bbox.max.x, bbox.max.y],
+      ],
+      { padding: 40, maxZoom: 14, animate: false },
+    );
+  } else if (centroid && map.getZoom() < 9) {
     map.jumpTo({ center: [centroid.x, centroid.y], zoom: 10.5 });
   }
+  setupRegions(map, regions);
 };
 
-export const fetchData = (map) => {
+export const fetchData = (map, url) => {
   $("loading").style.display = "flex";
   $("loading").style.display = "flex";
-  fetch("/data")
+  return fetch(url)
     .then((res) => {
       if (!res.ok) {
-        $("legend").style.display = "none";
+        if ($("legend")) $("legend").style.display = "none";
         if (res.status === 401) {
           $("error401").style.display = "flex";
         } else if (res.status === 503) {
@@ -214,7 +274,7 @@ export const fetchData = (map) => {
     .then((res) => processData(map, res))
     .catch((err) => {
       if (err.message !== "backend") {
-        $("legend").style.display = "none";
+        if ($("legend")) $("legend").style.display = "none";
         $("error500").style.display = "flex";
         console.err("failed to parse backend data", err);
       } else {
@@ -226,30 +286,83 @@ export const fetchData = (map) => {
     });
 };
 
+const showSync = ({ status, fetched, total }) => {
+  const text =
+    status === "queued"
+      ? "Waiting to fetch your activities..."
+      : total
+        ? `Fetched ${fetched} of ~${total} activities`
+        : `Fetched ${fetched} activities`;
+  $("sync-text").textContent = text;
+  const pct = total ? Math.min(100, (100 * fetched) / total) : 0;
+  $("sync-bar").style.width = `${pct}%`;
+  $("sync").style.display = "block";
+};
+
+const addCells = (map, cells) => {
+  allCells = allCells.concat(cells);
+  map.getSource("hex")?.setData(makeHexes(allCells));
+};
+
+/// Show the progress of any sync in the background, adding hexagons
+/// as they're found, and reload the data once it's done
+export const watchSync = (map, url) => {
+  // Anything from before the stream was opened
+  fetch("/sync/status")
+    .then((res) => (res.ok ? res.json() : null))
+    .then((job) => {
+      if (job && (job.status === "queued" || job.status === "running")) {
+        showSync(job);
+      }
+    })
+    .catch((err) => console.error("failed to get sync status", err));
+
+  const events = new EventSource("/events");
+  events.addEventListener("progress", (e) =>
+    showSync({ status: "running", ...JSON.parse(e.data) }),
+  );
+  events.addEventListener("cells", (e) =>
+    addCells(map, JSON.parse(e.data).cells),
+  );
+  events.addEventListener("done", () => {
+    $("sync").style.display = "none";
+    fetchData(map, url);
+  });
+  events.addEventListener("failed", (e) => {
+    $("sync-text").textContent = `Problem fetching activities: ${JSON.parse(e.data).message}`;
+    $("sync").style.display = "block";
+  });
+};
+
 let selectedId = null;
 
 export const mapInteractions = (map) => {
   map.on("click", "activities", (e) => {
     e.preventDefault();
-    const props = e.features?.[0]?.properties;
+    const feature = e.features?.[0]?.properties;
+    const props = activityProps.get(feature?.id) || feature;
     $("p-id").href = `https://www.strava.com/activities/${props.id}`;
     $("p-name").innerText = props.name;
     $("p-date").innerText = fmtDate(props.start_date);
     $("p-distance").innerText = fmtDist(props.distance);
     $("p-moving").innerText = fmtTime(props.moving_time);
     $("p-type").innerText = fmtActivity(props.sport_type);
+    $("p-cells").innerText =
+      props.total_cells !== undefined
+        ? `${props.new_cells} new of ${props.total_cells} hexagons`
+        : "";
     $("props").style.display = "block";
 
     if (e.features.length > 0) {
       if (selectedId !== null) {
         map.setFeatureState(
-          { source: "activities", id: selectedId },
+          { source: "activities", sourceLayer: "activities", id: selectedId },
           { selected: false },
         );
       }
       selectedId = e.features[0].id;
       map.setFeatureState(
-        { source: "activities", id: selectedId },
+        { source: "activities", sourceLayer: "activities", id: selectedId },
         { selected: true },
       );
     }
@@ -260,7 +373,7 @@ export const mapInteractions = (map) => {
       $("props").style.display = "none";
       if (selectedId !== null) {
         map.setFeatureState(
-          { source: "activities", id: selectedId },
+          { source: "activities", sourceLayer: "activities", id: selectedId },
           { selected: false },
         );
       }
@@ -1,7 +1,17 @@
-use dotenvy;
-use rocket::{http::Status, local::blocking::Client};
+use std::sync::OnceLock;
+use std::time::Duration;
+use std::{env, fs};
 
-use hexy::routes;
+use diesel_migrations::MigrationHarness;
+use httpmock::MockServer;
+use rocket::http::{Cookie, Status};
+use rocket::local::blocking::Client;
+
+use geo::Intersects;
+use h3o::LatLng;
+use hexy::events::Events;
+use hexy::models::{CellDb, NewGroupDb};
+use hexy::{db, jobs, routes, strava, sync, tiles};
 
 #[test]
 fn test_create_user() {
@@ -12,3 +22,376 @@ fn test_create_user() {
     let response = req.dispatch();
     assert_eq!(response.status(), Status::SeeOther);
 }
+
+/// A client with its own freshly migrated database, so tests don't share one
+async fn setup(name: &str) -> (rocket::local::asynchronous::Client, db::Db) {
+    dotenvy::from_filename("test.env").ok();
+    let path = env::temp_dir().join(format!("hexy-{}.sqlite", name));
+    let _ = fs::remove_file(&path);
+    let figment = rocket::Config::figment().merge(("databases.db.url", path.to_str().unwrap()));
+    let s = routes::build(false).configure(figment);
+    let client = rocket::local::asynchronous::Client::tracked(s)
+        .await
+        .unwrap();
+    let conn = db::Db::get_one(client.rocket()).await.unwrap();
+    conn.run(|c| {
+        c.run_pending_migrations(db::MIGRATIONS)
+            .map(|_| ())
+            .unwrap()
+    })
+    .await;
+    (client, conn)
+}
+
+/// Strava for the routes, which use `StravaClient::default()` and so read it from the env
+/// Shared by every test, so each one's mocks should match its own user's token
+fn strava_server() -> &'static MockServer {
+    static SERVER: OnceLock<MockServer> = OnceLock::new();
+    SERVER.get_or_init(|| {
+        let server = MockServer::start();
+        env::set_var("STRAVA_BASE", server.url("/"));
+        server
+    })
+}
+
+/// Each user's access token is `access-<id>`
+async fn add_user(conn: &db::Db, id: i32) {
+    let token = strava::TokenResponse {
+        athlete: strava::Athlete { id },
+        refresh_token: "refresh".to_string(),
+        access_token: format!("access-{}", id),
+        expires_at: i32::MAX,
+    };
+    db::save_user(conn, &token).await.unwrap();
+}
+
+#[rocket::async_test]
+async fn test_owner_leaves_group() {
+    let (_client, conn) = setup("owner-leaves").await;
+    for id in [1, 2, 3] {
+        add_user(&conn, id).await;
+    }
+    let group = NewGroupDb {
+        name: "Club".to_string(),
+        invite_code: "abc".to_string(),
+        owner_id: 1,
+        created_at: 0,
+    };
+    let group = db::create_group(&conn, group).await.unwrap();
+    db::join_group(&conn, 3, "abc".to_string(), 10)
+        .await
+        .unwrap();
+    db::join_group(&conn, 2, "abc".to_string(), 20)
+        .await
+        .unwrap();
+
+    // Goes to whoever joined first
+    assert_eq!(db::leave_group(&conn, 1, group.id).await.unwrap(), 1);
+    let got = db::get_group(&conn, 3, group.id).await.unwrap().unwrap();
+    assert_eq!(got.owner_id, 3);
+
+    // Nobody left to take it
+    db::leave_group(&conn, 2, group.id).await.unwrap();
+    db::leave_group(&conn, 3, group.id).await.unwrap();
+    assert!(db::join_group(&conn, 1, "abc".to_string(), 30)
+        .await
+        .unwrap()
+        .is_none());
+}
+
+/// Strava's JSON for activities with these ids, a minute apart
+fn activities_json(ids: std::ops::Range<i64>) -> String {
+    let activities: Vec<serde_json::Value> = ids
+        .map(|id| {
+            serde_json::json!({
+                "id": id,
+                "name": format!("Activity {}", id),
+                "distance": 1000.0,
+                "moving_time": 300,
+                "elapsed_time": 300,
+                "start_date": chrono::DateTime::from_timestamp(1_700_000_000 + 60 * id, 0),
+                "kudos_count": 0,
+                "average_speed": 3.3,
+                "sport_type": "Run",
+                "map": {"summary_polyline": null},
+            })
+        })
+        .collect();
+    serde_json::to_string(&activities).unwrap()
+}
+
+#[rocket::async_test]
+async fn test_sync_pages() {
+    let (_client, conn) = setup("sync-pages").await;
+    add_user(&conn, 1).await;
+    let first: Vec<strava::ActivityResponse> =
+        serde_json::from_str(&activities_json(0..1)).unwrap();
+    db::save_activities(&conn, 1, &first).await.unwrap();
+
+    // More than a page of new activities since the last one
+    let server = MockServer::start();
+    let path = "/api/v3/athlete/activities";
+    let after = "1700000000";
+    let page1 = server.mock(|when, then| {
+        when.path(path)
+            .query_param("after", after)
+            .query_param("page", "1");
+        then.status(200).body(activities_json(1..201));
+    });
+    let page2 = server.mock(|when, then| {
+        when.path(path)
+            .query_param("after", after)
+            .query_param("page", "2");
+        then.status(200).body(activities_json(201..206));
+    });
+    let client = strava::StravaClient::new(&server.url("/"), "", "", "");
+    assert_eq!(
+        sync::sync(&conn, &client, None, 1, "access").await.unwrap(),
+        205
+    );
+    page1.assert();
+    page2.assert();
+    assert_eq!(db::get_activities(&conn, 1).await.unwrap().len(), 206);
+}
+
+#[rocket::async_test]
+async fn test_delete_user_during_job() {
+    let (client, conn) = setup("delete-during-job").await;
+    add_user(&conn, 1).await;
+    db::enqueue_job(&conn, 1, 0).await.unwrap();
+
+    // Slow enough to delete the user while the job waits for the first page
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.path("/api/v3/athlete/activities")
+            .query_param("page", "1");
+        then.status(200)
+            .delay(Duration::from_millis(500))
+            .body(activities_json(0..200));
+    });
+    let strava = strava::StravaClient::new(&server.url("/"), "", "", "");
+    let worker = db::Db::get_one(client.rocket()).await.unwrap();
+    let job = rocket::tokio::spawn(async move {
+        let budget = sync::Budget::default();
+        jobs::work_once(&worker, &Events::default(), &strava, &budget).await
+    });
+    rocket::tokio::time::sleep(Duration::from_millis(100)).await;
+    db::delete_user(&conn, 1).await.unwrap();
+
+    assert_eq!(job.await.unwrap().unwrap(), Some("cancelled"));
+    assert!(db::get_activities(&conn, 1).await.unwrap().is_empty());
+    assert!(db::get_cells(&conn, 1).await.unwrap().is_empty());
+    assert!(db::get_jobs(&conn, 1).await.unwrap().is_empty());
+}
+
+#[rocket::async_test]
+async fn test_has_done_job() {
+    let (_client, conn) = setup("done-job").await;
+    add_user(&conn, 1).await;
+    assert!(!db::has_done_job(&conn, 1).await.unwrap());
+
+    let job = db::enqueue_job(&conn, 1, 0).await.unwrap();
+    db::claim_job(&conn, 0).await.unwrap();
+    assert!(!db::has_done_job(&conn, 1).await.unwrap());
+    db::finish_job(&conn, job.id, "done", None, 0)
+        .await
+        .unwrap();
+    assert!(db::has_done_job(&conn, 1).await.unwrap());
+}
+
+#[rocket::async_test]
+async fn test_save_many_activities() {
+    let (_client, conn) = setup("many-activities").await;
+    add_user(&conn, 1).await;
+    // Several chunks' worth, as a whole history can be this long
+    let activities: Vec<strava::ActivityResponse> =
+        serde_json::from_str(&activities_json(0..3000)).unwrap();
+    assert_eq!(
+        db::save_activities(&conn, 1, &activities).await.unwrap(),
+        3000
+    );
+    assert_eq!(db::get_activities(&conn, 1).await.unwrap().len(), 3000);
+}
+
+/// How many activities `/data` responds with for the logged in user, or the error status
+async fn count_data(
+    client: &rocket::local::asynchronous::Client,
+    id: i32,
+    query: &str,
+) -> Result<usize, Status> {
+    let response = client
+        .get(format!("/data?{}", query))
+        .private_cookie(Cookie::new("id", id.to_string()))
+        .dispatch()
+        .await;
+    if response.status() != Status::Ok {
+        return Err(response.status());
+    }
+    let data: serde_json::Value = response.into_json().await.unwrap();
+    Ok(data["activities"]["features"].as_array().unwrap().len())
+}
+
+#[rocket::async_test]
+async fn test_data_rejects_bad_filters() {
+    let (client, conn) = setup("bad-filters").await;
+    add_user(&conn, 33).await;
+    let activities: Vec<strava::ActivityResponse> =
+        serde_json::from_str(&activities_json(0..3)).unwrap();
+    db::save_activities(&conn, 33, &activities).await.unwrap();
+    strava_server().mock(|when, then| {
+        when.path("/api/v3/athlete/activities")
+            .header("Authorization", "Bearer access-33");
+        then.status(200).body("[]");
+    });
+
+    assert_eq!(count_data(&client, 33, "").await, Ok(3));
+    assert_eq!(count_data(&client, 33, "after=2030-01-01").await, Ok(0));
+    assert_eq!(count_data(&client, 33, "min_distance=5000").await, Ok(0));
+    // Rather than quietly ignoring the filter and sending everything
+    for query in [
+        "after=garbage",
+        "before=2024-13-01",
+        "min_distance=abc",
+        "bbox=1,2",
+    ] {
+        assert_eq!(
+            count_data(&client, 33, query).await,
+            Err(Status::UnprocessableEntity),
+            "{}",
+            query
+        );
+    }
+}
+
+#[rocket::async_test]
+async fn test_data_after_empty_history() {
+    let (client, conn) = setup("empty-history").await;
+    add_user(&conn, 49).await;
+    // Their history was fetched before they'd recorded anything
+    let job = db::enqueue_job(&conn, 49, 0).await.unwrap();
+    db::claim_job(&conn, 0).await.unwrap();
+    db::finish_job(&conn, job.id, "done", None, 0)
+        .await
+        .unwrap();
+    assert!(db::get_activities(&conn, 49).await.unwrap().is_empty());
+
+    // Then they record their first activity
+    let latest = strava_server().mock(|when, then| {
+        when.path("/api/v3/athlete/activities")
+            .header("Authorization", "Bearer access-49");
+        then.status(200).body(activities_json(0..1));
+    });
+    assert_eq!(count_data(&client, 49, "").await, Ok(1));
+    latest.assert();
+    // Without queueing the whole history again
+    assert_eq!(db::get_jobs(&conn, 49).await.unwrap().len(), 1);
+}
+
+#[rocket::async_test]
+async fn test_sync_within_budget() {
+    let (_client, conn) = setup("sync-budget").await;
+    add_user(&conn, 1).await;
+    let first: Vec<strava::ActivityResponse> =
+        serde_json::from_str(&activities_json(0..1)).unwrap();
+    db::save_activities(&conn, 1, &first).await.unwrap();
+
+    // Three full pages waiting, but only two requests left
+    let server = MockServer::start();
+    let pages: Vec<_> = (0..3)
+        .map(|i| {
+            server.mock(|when, then| {
+                when.path("/api/v3/athlete/activities")
+                    .query_param("after", "1700000000")
+                    .query_param("page", (i + 1).to_string());
+                let start = 1 + 200 * i;
+                then.status(200).body(activities_json(start..start + 200));
+            })
+        })
+        .collect();
+    let client = strava::StravaClient::new(&server.url("/"), "", "", "");
+    let budget = sync::Budget::new(sync::Config {
+        interval: Duration::from_secs(60 * 60),
+        budget: 2,
+    });
+    let fetched = sync::sync(&conn, &client, Some(&budget), 1, "access").await;
+    assert_eq!(fetched.unwrap(), 400);
+    assert_eq!(budget.remaining(), 0);
+    pages[0].assert();
+    pages[1].assert();
+    pages[2].assert_hits(0);
+    assert_eq!(db::get_activities(&conn, 1).await.unwrap().len(), 401);
+}
+
+/// The realistic Strava responses the unit tests use
+fn fixture_activities() -> Vec<strava::ActivityResponse> {
+    serde_json::from_str(include_str!("fixtures/activities.json")).unwrap()
+}
+
+#[rocket::async_test]
+async fn test_tile_cells_in_ranges() {
+    let (_client, conn) = setup("tile-cells").await;
+    add_user(&conn, 1).await;
+    db::save_activities(&conn, 1, &fixture_activities())
+        .await
+        .unwrap();
+    db::update_cells(&conn, 1).await.unwrap();
+    let all = db::get_cells(&conn, 1).await.unwrap();
+
+    // Central London
+    let tile = tiles::TileId::new(14, 8186, 5448).unwrap();
+    let near = |cells: Vec<CellDb>| -> Vec<i64> {
+        let mut near: Vec<i64> = cells
+            .iter()
+            .filter(|c| {
+                let centre = LatLng::from(c.cell_index().unwrap());
+                tile.near().intersects(&geo::Coord::from(centre))
+            })
+            .map(|c| c.cell)
+            .collect();
+        near.sort();
+        near
+    };
+    let ranges = hexy::h3::ranges_within(tile.near()).unwrap();
+    let got = db::get_cells_in(&conn, 1, ranges).await.unwrap();
+    // Far fewer than all of them, but none of the ones in the tile missing
+    assert!(got.len() < all.len() / 2);
+    assert!(!near(got.clone()).is_empty());
+    assert_eq!(near(got), near(all));
+}
+
+#[rocket::async_test]
+async fn test_data_without_lines() {
+    let (client, conn) = setup("without-lines").await;
+    add_user(&conn, 27).await;
+    db::save_activities(&conn, 27, &fixture_activities())
+        .await
+        .unwrap();
+    strava_server().mock(|when, then| {
+        when.path("/api/v3/athlete/activities")
+            .header("Authorization", "Bearer access-27");
+        then.status(200).body("[]");
+    });
+
+    let features = |lines: &'static str| {
+        let client = &client;
+        async move {
+            let response = client
+                .get(format!("/data?lines={}", lines))
+                .private_cookie(Cookie::new("id", "27"))
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Ok);
+            let data: serde_json::Value = response.into_json().await.unwrap();
+            data["activities"]["features"].as_array().unwrap().clone()
+        }
+    };
+    let with = features("true").await;
+    let without = features("false").await;
+    assert_eq!(with.len(), without.len());
+    assert!(with.iter().any(|f| !f["geometry"].is_null()));
+    assert!(without.iter().all(|f| f["geometry"].is_null()));
+    // But still everything shown when an activity is clicked
+    for (a, b) in with.iter().zip(&without) {
+        assert_eq!(a["properties"], b["properties"]);
+    }
+}